@@ -0,0 +1,43 @@
+#!/bin/sh
+# Adam Short
+# 08/05/2020
+#
+# Turn `nm -n -C` output on stdin into an assembly file holding the
+# kernel's symbol table, for backtrace.rs. Only code symbols go in,
+# sorted by address. With nothing on stdin, the table is empty, which
+# is what the first link uses.
+
+awk 'BEGIN { n = 0 }
+$2 ~ /^[tTwW]$/ && $1 ~ /^[0-9a-fA-F]+$/ {
+	name = $3
+	for (i = 4; i <= NF; i++)
+		name = name " " $i
+	gsub(/\\/, "\\\\", name)
+	gsub(/"/, "\\\"", name)
+	addrs[n] = $1
+	names[n] = name
+	n++
+}
+END {
+	print "# Generated by scripts/ksyms.sh. Do not edit."
+	print ".section .rodata.ksyms, \"a\""
+	print ".balign 8"
+	print ".global _ksyms_num"
+	print "_ksyms_num: .dword " n + 0
+	print ".global _ksyms_addrs"
+	print "_ksyms_addrs:"
+	for (i = 0; i < n; i++)
+		print "\t.dword 0x" addrs[i]
+	print ".global _ksyms_names"
+	print "_ksyms_names:"
+	off = 0
+	for (i = 0; i < n; i++) {
+		print "\t.word " off
+		off += length(names[i]) + 1
+	}
+	print ".global _ksyms_strings"
+	print "_ksyms_strings:"
+	for (i = 0; i < n; i++)
+		print "\t.asciz \"" names[i] "\""
+}
+'
@@ -0,0 +1,112 @@
+// Adam Short
+// 08/05/2020
+
+use crate::cpu::TrapFrame;
+
+// The symbol table scripts/ksyms.sh makes between links: code addresses
+// in order, and where each one's name starts in the strings.
+extern "C" {
+    static _ksyms_num: usize;
+    static _ksyms_addrs: [usize; 0];
+    static _ksyms_names: [u32; 0];
+    static _ksyms_strings: [u8; 0];
+    static TEXT_START: usize;
+    static TEXT_END: usize;
+    static KERNEL_STACK_START: usize;
+    static HEAP_START: usize;
+    static HEAP_SIZE: usize;
+}
+
+// Give up after this many frames, in case the chain loops.
+const MAX_FRAMES: usize = 32;
+
+const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+// The function addr is in, and how far into it
+pub fn lookup(addr: usize) -> Option<(&'static str, usize)> {
+    unsafe {
+        if addr < TEXT_START || addr >= TEXT_END || _ksyms_num == 0 {
+            return None;
+        }
+        let addrs = core::slice::from_raw_parts(_ksyms_addrs.as_ptr(), _ksyms_num);
+        // The last symbol that starts at or before addr
+        let i = match addrs.binary_search(&addr) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1
+        };
+        let start = _ksyms_strings.as_ptr().add(*_ksyms_names.as_ptr().add(i) as usize);
+        let mut len = 0;
+        while *start.add(len) != 0 {
+            len += 1;
+        }
+        let name = core::str::from_utf8(core::slice::from_raw_parts(start, len)).ok()?;
+        Some((name, addr - addrs[i]))
+    }
+}
+
+fn print_frame(n: usize, pc: usize) {
+    match lookup(pc) {
+        Some((name, off)) => println!("  #{:<2} 0x{:016x} {}+0x{:x}", n, pc, name, off),
+        None => println!("  #{:<2} 0x{:016x} ??", n, pc)
+    }
+}
+
+// Frame pointers only ever point into the kernel's stacks or the heap,
+// where the trap stacks are.
+fn is_valid_fp(fp: usize) -> bool {
+    unsafe { fp % 8 == 0 && fp >= KERNEL_STACK_START && fp <= HEAP_START + HEAP_SIZE }
+}
+
+// Walk the stack from pc and frame pointer fp. With frame pointers on,
+// every function keeps the return address at fp - 8 and its caller's
+// frame pointer at fp - 16. The stack grows down, so each caller's
+// frame is above the last one; if it isn't, we've run off the end.
+pub fn print_backtrace_from(pc: usize, mut fp: usize) {
+    println!("Backtrace:");
+    print_frame(0, pc);
+    for n in 1..MAX_FRAMES {
+        if !is_valid_fp(fp) {
+            break;
+        }
+        let (ra, prev) = unsafe { (*((fp - 8) as *const usize), *((fp - 16) as *const usize)) };
+        if ra == 0 {
+            break;
+        }
+        // ra is the instruction after the call, which can be the start
+        // of the next function. The call itself is what we want.
+        print_frame(n, ra - 4);
+        if prev <= fp {
+            break;
+        }
+        fp = prev;
+    }
+}
+
+// Backtrace from wherever we are now
+pub fn print_backtrace() {
+    let pc: usize;
+    let fp: usize;
+    unsafe {
+        asm!("auipc $0, 0
+              mv $1, s0" : "=r"(pc), "=r"(fp) ::: "volatile");
+    }
+    print_backtrace_from(pc, fp);
+}
+
+// Every general purpose register in a trap frame, four to a line
+pub fn dump_registers(frame: &TrapFrame) {
+    for row in 0..8 {
+        for col in 0..4 {
+            let r = row * 4 + col;
+            print!(" {:>4}: 0x{:016x}", REG_NAMES[r], frame.regs[r]);
+        }
+        println!();
+    }
+    println!("   pc: 0x{:016x} satp: 0x{:016x} hart: {}", frame.pc, frame.satp, frame.hartid);
+}
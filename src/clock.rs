@@ -0,0 +1,131 @@
+// Adam Short
+// 08/04/2020
+
+use crate::fdt;
+use crate::page::zalloc;
+use crate::sync::Spinlock;
+use crate::timer::{get_mtime, ticks_to_ns, timebase};
+use core::sync::atomic::{fence, Ordering};
+
+// Clock ids, as Linux numbers them
+pub const CLOCK_REALTIME: usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+// The goldfish RTC on QEMU's virt board keeps nanoseconds since the
+// epoch. Reading TIME_LOW latches TIME_HIGH, so low goes first.
+const GOLDFISH_RTC: usize = 0x0010_1000;
+const RTC_TIME_LOW: usize = 0x00;
+const RTC_TIME_HIGH: usize = 0x04;
+
+// Where every process finds the clock page. It's read-only for them.
+pub const VDSO_ADDR: usize = 0x3000_0000;
+
+// What's in the clock page. The monotonic clock is the time counter
+// (rdtime, which reads mtime) scaled by the timebase, and the real-time
+// clock is that plus realtime_offset. seq is odd while the kernel is in
+// the middle of changing things, so readers try again if it's odd or
+// changed under them.
+#[repr(C)]
+pub struct VdsoData {
+    seq: u64,
+    timebase: u64,
+    realtime_offset: u64,
+}
+
+static mut VDSO_PAGE: *mut VdsoData = core::ptr::null_mut();
+// Only one writer at a time
+static VDSO_LOCK: Spinlock<()> = Spinlock::new(());
+
+fn rtc_read(base: usize) -> u64 {
+    unsafe {
+        let low = ((base + RTC_TIME_LOW) as *const u32).read_volatile() as u64;
+        let high = ((base + RTC_TIME_HIGH) as *const u32).read_volatile() as u64;
+        high << 32 | low
+    }
+}
+
+// Set up the clock page and start the real-time clock at whatever the
+// RTC says.
+pub fn init() {
+    let rtc = fdt::find_compatible("google,goldfish-rtc")
+                  .first()
+                  .and_then(|n| n.reg())
+                  .map_or(GOLDFISH_RTC, |(addr, _)| addr);
+    unsafe {
+        VDSO_PAGE = zalloc(1) as *mut VdsoData;
+        (*VDSO_PAGE).timebase = timebase() as u64;
+    }
+    set_realtime_ns(rtc_read(rtc));
+}
+
+// The physical page to map at VDSO_ADDR
+pub fn vdso_page() -> usize {
+    unsafe { VDSO_PAGE as usize }
+}
+
+pub fn monotonic_ns() -> u64 {
+    ticks_to_ns(get_mtime()) as u64
+}
+
+pub fn realtime_ns() -> u64 {
+    unsafe { monotonic_ns().wrapping_add((*VDSO_PAGE).realtime_offset) }
+}
+
+pub fn set_realtime_ns(ns: u64) {
+    let _guard = VDSO_LOCK.lock();
+    unsafe {
+        let data = &mut *VDSO_PAGE;
+        let seq = core::ptr::read_volatile(&data.seq);
+        core::ptr::write_volatile(&mut data.seq, seq + 1);
+        fence(Ordering::Release);
+        core::ptr::write_volatile(&mut data.realtime_offset,
+                                  ns.wrapping_sub(monotonic_ns()));
+        fence(Ordering::Release);
+        core::ptr::write_volatile(&mut data.seq, seq + 2);
+    }
+}
+
+// The resolution of both clocks: one tick of the timebase, rounded up.
+pub fn resolution_ns() -> u64 {
+    (NS_PER_SEC + timebase() as u64 - 1) / timebase() as u64
+}
+
+pub fn gettime(clock: usize) -> Option<u64> {
+    match clock {
+        CLOCK_REALTIME => Some(realtime_ns()),
+        CLOCK_MONOTONIC => Some(monotonic_ns()),
+        _ => None
+    }
+}
+
+// The user side of the clock page. Processes call this instead of the
+// clock_gettime system call, and it never traps: the time counter is
+// readable from user mode and everything else is in the page.
+pub fn vdso_gettime(clock: usize) -> Option<u64> {
+    let data = VDSO_ADDR as *const VdsoData;
+    loop {
+        unsafe {
+            let seq = core::ptr::read_volatile(&(*data).seq);
+            if seq & 1 != 0 {
+                continue;
+            }
+            fence(Ordering::Acquire);
+            let timebase = core::ptr::read_volatile(&(*data).timebase);
+            let offset = core::ptr::read_volatile(&(*data).realtime_offset);
+            let ticks: u64;
+            asm!("rdtime $0" : "=r"(ticks) ::: "volatile");
+            fence(Ordering::Acquire);
+            if core::ptr::read_volatile(&(*data).seq) != seq {
+                continue;
+            }
+            let mono = (ticks as u128 * NS_PER_SEC as u128 / timebase as u128) as u64;
+            return match clock {
+                CLOCK_REALTIME => Some(mono.wrapping_add(offset)),
+                CLOCK_MONOTONIC => Some(mono),
+                _ => None
+            };
+        }
+    }
+}
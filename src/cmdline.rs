@@ -0,0 +1,99 @@
+// Adam Short
+// 08/05/2020
+
+use crate::fdt;
+use crate::{console, gdbstub, log, process, sched};
+use alloc::vec::Vec;
+
+// A kernel parameter, as in name=value on the command line. A parameter
+// given without a value, like "quiet", gets "". set parses the value and
+// returns false if it's no good.
+pub struct Param {
+    pub name: &'static str,
+    pub set: fn(&'static str) -> bool,
+    pub help: &'static str,
+}
+
+// Every subsystem that takes parameters lists them in a PARAMS table of
+// its own, and the table goes here.
+const PARAMS: &[&[Param]] = &[
+    &console::PARAMS,
+    &gdbstub::PARAMS,
+    &log::PARAMS,
+    &process::PARAMS,
+    &sched::PARAMS,
+];
+
+static mut BOOTARGS: &str = "";
+
+// Read /chosen/bootargs, which QEMU fills in from -append, and hand each
+// parameter to whoever registered it. Runs in kinit, once the heap is
+// up and before anything that reads a parameter.
+pub fn init() {
+    let bootargs = fdt::find_node("/chosen").and_then(|n| n.prop_str("bootargs")).unwrap_or("");
+    unsafe { BOOTARGS = bootargs; }
+    for (name, value) in parse(bootargs) {
+        match PARAMS.iter().flat_map(|t| t.iter()).find(|p| p.name == name) {
+            Some(param) => {
+                if !(param.set)(value) {
+                    warn!("Bad value for kernel parameter {}: \"{}\" ({})", name, value, param.help);
+                }
+            },
+            None => warn!("Unknown kernel parameter {}", name)
+        }
+    }
+}
+
+// The whole command line, as it came
+pub fn bootargs() -> &'static str {
+    unsafe { BOOTARGS }
+}
+
+// Split a command line into (name, value) pairs. Values can be quoted
+// to get spaces into them: init="/bin/sh -l".
+pub fn parse(args: &'static str) -> Vec<(&'static str, &'static str)> {
+    let mut params = Vec::new();
+    let mut rest = args.trim_start();
+    while !rest.is_empty() {
+        let mut quoted = false;
+        let end = rest.char_indices()
+                      .find(|&(_, c)| {
+                          if c == '"' {
+                              quoted = !quoted;
+                          }
+                          c.is_whitespace() && !quoted
+                      })
+                      .map_or(rest.len(), |(i, _)| i);
+        let param = &rest[..end];
+        let (name, value) = match param.find('=') {
+            Some(i) => (&param[..i], &param[i + 1..]),
+            None => (param, "")
+        };
+        params.push((name, value.trim_matches('"')));
+        rest = rest[end..].trim_start();
+    }
+    params
+}
+
+// ///////////////////////////////////
+// / VALUE PARSERS
+// ///////////////////////////////////
+
+// Decimal, or hex with 0x
+pub fn parse_usize(value: &str) -> Option<usize> {
+    if value.starts_with("0x") {
+        usize::from_str_radix(&value[2..], 16).ok()
+    }
+    else {
+        value.parse().ok()
+    }
+}
+
+// A flag on its own means yes.
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "" | "1" | "y" | "yes" | "on" | "true" => Some(true),
+        "0" | "n" | "no" | "off" | "false" => Some(false),
+        _ => None
+    }
+}
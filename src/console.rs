@@ -0,0 +1,221 @@
+// Adam Short
+// 08/05/2020
+
+use crate::cmdline::Param;
+use crate::sync::Spinlock;
+use crate::uart::{self, Port, UART0};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{Arguments, Error, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// Somewhere the kernel's output can go. Writes come from print!, which
+// can't sleep, so they have to be done by the time write_str returns.
+pub trait Console: Sync {
+    fn name(&self) -> &str;
+    fn write_str(&self, s: &str);
+    // The UART behind this console, if there is one
+    fn port(&self) -> Option<&'static Port> {
+        None
+    }
+}
+
+// ///////////////////////////////////
+// / CONSOLES
+// ///////////////////////////////////
+
+pub struct UartConsole {
+    name: String,
+    port: &'static Port,
+}
+
+impl Console for UartConsole {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn write_str(&self, s: &str) {
+        self.port.write_str_sync(s);
+    }
+    fn port(&self) -> Option<&'static Port> {
+        Some(self.port)
+    }
+}
+
+// Keeps the last RAMLOG_SIZE bytes of output in memory, so there's a
+// record even when nothing is listening on a UART.
+const RAMLOG_SIZE: usize = 16 * 1024;
+
+pub struct RamConsole {
+    log: Spinlock<RamLog>,
+}
+
+struct RamLog {
+    buf: [u8; RAMLOG_SIZE],
+    // Where the next byte goes, and how many have ever been written
+    head: usize,
+    written: usize,
+}
+
+pub static RAMLOG: RamConsole = RamConsole {
+    log: Spinlock::new(RamLog { buf: [0; RAMLOG_SIZE], head: 0, written: 0 }),
+};
+
+impl RamConsole {
+    // Copy out what's in the log, oldest first. Returns how much there
+    // was.
+    pub fn read(&self, out: &mut [u8]) -> usize {
+        let log = self.log.lock();
+        let len = log.written.min(RAMLOG_SIZE);
+        let start = (log.head + RAMLOG_SIZE - len) % RAMLOG_SIZE;
+        let n = len.min(out.len());
+        for (i, b) in out[..n].iter_mut().enumerate() {
+            *b = log.buf[(start + i) % RAMLOG_SIZE];
+        }
+        n
+    }
+}
+
+impl Console for RamConsole {
+    fn name(&self) -> &str {
+        "ram"
+    }
+    fn write_str(&self, s: &str) {
+        let mut log = self.log.lock();
+        for &b in s.as_bytes() {
+            let head = log.head;
+            log.buf[head] = b;
+            log.head = (head + 1) % RAMLOG_SIZE;
+            log.written += 1;
+        }
+    }
+}
+
+// Throws everything away, for console=null
+pub struct NullConsole;
+
+pub static NULL: NullConsole = NullConsole;
+
+impl Console for NullConsole {
+    fn name(&self) -> &str {
+        "null"
+    }
+    fn write_str(&self, _s: &str) {}
+}
+
+// ///////////////////////////////////
+// / REGISTRY
+// ///////////////////////////////////
+
+struct Registered {
+    console: &'static dyn Console,
+    active: bool,
+}
+
+// Every console we know about. print! writes to the active ones while
+// holding the lock, so lines from different harts don't get mixed up.
+static CONSOLES: Spinlock<Vec<Registered>> = Spinlock::new(Vec::new());
+
+pub fn register_console(console: &'static dyn Console, active: bool) {
+    CONSOLES.lock().push(Registered { console, active });
+}
+
+pub fn find_console(name: &str) -> Option<&'static dyn Console> {
+    CONSOLES.lock().iter().find(|r| r.console.name() == name).map(|r| r.console)
+}
+
+// Turn a console on or off. Returns false if there isn't one by that
+// name.
+pub fn set_active(name: &str, active: bool) -> bool {
+    let mut consoles = CONSOLES.lock();
+    match consoles.iter_mut().find(|r| r.console.name() == name) {
+        Some(r) => { r.active = active; true },
+        None => false
+    }
+}
+
+pub fn is_active(name: &str) -> bool {
+    CONSOLES.lock().iter().any(|r| r.active && r.console.name() == name)
+}
+
+// Kernel parameters. console= can be given more than once.
+pub const PARAMS: [Param; 1] = [
+    Param { name: "console", set: param_console, help: "ttyS<n>, ram or null" },
+];
+
+// The consoles the command line asked for. They don't exist yet when it
+// is parsed, so init() turns them on.
+static CHOSEN: Spinlock<Vec<&'static str>> = Spinlock::new(Vec::new());
+
+fn param_console(value: &'static str) -> bool {
+    // console=ttyS0,115200 names a speed too, which we leave alone.
+    match value.split(',').next() {
+        Some(name) if !name.is_empty() => { CHOSEN.lock().push(name); true },
+        _ => false
+    }
+}
+
+// Register a console for every UART, the RAM log and the null console,
+// then turn on the ones the command line asked for. Without any, it's
+// the first UART and the RAM log. Runs in kinit, after cmdline::init.
+pub fn init() {
+    uart::probe();
+    for (n, port) in uart::ports().into_iter().enumerate() {
+        let console = UartConsole { name: format!("ttyS{}", n), port };
+        register_console(Box::leak(Box::new(console)), false);
+    }
+    register_console(&RAMLOG, false);
+    register_console(&NULL, false);
+
+    let mut chosen = false;
+    for name in core::mem::replace(&mut *CHOSEN.lock(), Vec::new()) {
+        if set_active(name, true) {
+            chosen = true;
+        }
+        else {
+            warn!("No console called {}", name);
+        }
+    }
+    if !chosen {
+        set_active("ttyS0", true);
+        set_active("ram", true);
+    }
+}
+
+struct ConsoleWriter<'a> {
+    consoles: &'a [Registered],
+}
+
+impl<'a> Write for ConsoleWriter<'a> {
+    fn write_str(&mut self, s: &str) -> Result<(), Error> {
+        for r in self.consoles.iter().filter(|r| r.active) {
+            r.console.write_str(s);
+        }
+        Ok(())
+    }
+}
+
+// Set once the kernel panics. The hart that panicked could be holding
+// the console lock or a port's, and neither can be taken twice, so from
+// then on everything goes to the first UART without any locks.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+pub fn start_panic() {
+    PANICKING.store(true, Ordering::SeqCst);
+}
+
+// What print! goes through. Until init() has registered anything, it's
+// straight to the first UART.
+pub fn _print(args: Arguments) {
+    if PANICKING.load(Ordering::Relaxed) {
+        UART0.write_panic(args);
+        return;
+    }
+    let consoles = CONSOLES.lock();
+    if consoles.is_empty() {
+        UART0.write_sync(args);
+        return;
+    }
+    let _ = ConsoleWriter { consoles: &consoles }.write_fmt(args);
+}
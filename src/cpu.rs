@@ -4,6 +4,7 @@
 use core::ptr::null_mut;
 
 #[repr(usize)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum SatpMode {
     Off =  0,
     Sv39 = 8,
@@ -17,7 +18,14 @@ pub struct TrapFrame {
     pub fregs:      [usize; 32],
     pub satp:       usize,
     pub trap_stack: *mut u8,
-    pub hartid:     usize
+    pub hartid:     usize,
+    // Where to resume, saved from sepc on every trap
+    pub pc:         usize,
+    pub fcsr:       usize,
+    // FS to run with: FS_OFF until the process first uses the FPU
+    pub fp_state:   usize,
+    // The hart whose FP registers were last loaded from this frame
+    pub fp_hart:    usize
 }
 
 impl TrapFrame {
@@ -28,11 +36,48 @@ impl TrapFrame {
             satp:       0,
             trap_stack: null_mut(),
             hartid:      0,
+            pc:         0,
+            fcsr:       0,
+            fp_state:   FS_OFF,
+            fp_hart:    0,
         }
     }
 }
 
-pub static mut KERNEL_TRAP_FRAME: [TrapFrame; 8] = [TrapFrame::zero(); 8];
+// The most harts we support. QEMU's virt board has at most eight.
+pub const MAX_HARTS: usize = 8;
+
+// Each hart's machine-mode trap frame. mscratch points here, and the
+// trap stack is what m_trap runs on.
+pub static mut KERNEL_TRAP_FRAME: [TrapFrame; MAX_HARTS] = [TrapFrame::zero(); MAX_HARTS];
+
+// Interrupt bits shared by mie/mip and sie/sip
+pub const SSIP: usize = 1 << 1;
+pub const MSIP: usize = 1 << 3;
+pub const STIP: usize = 1 << 5;
+pub const MTIP: usize = 1 << 7;
+pub const SEIP: usize = 1 << 9;
+
+// Supervisor interrupt enable bit in sstatus
+pub const SSTATUS_SIE: usize = 1 << 1;
+
+// Values of the FS field (bits 14:13) of sstatus
+pub const FS_OFF: usize = 0;
+pub const FS_CLEAN: usize = 2;
+pub const FS_SHIFT: usize = 13;
+
+// For each hart, the trap frame whose FP state is in its FP registers
+static mut FP_OWNER: [usize; MAX_HARTS] = [0; MAX_HARTS];
+
+extern "C" {
+    fn fp_load(frame: usize);
+}
+
+// Calls the kernel makes into machine mode with ecall. The number goes
+// in a7 like it does for the SBI.
+pub const SBI_SET_TIMER: usize = 0;
+// What a0 comes back with for a call machine mode doesn't know
+pub const SBI_ERR_NOT_SUPPORTED: isize = -2;
 
 pub const fn build_satp(mode: SatpMode, asid: usize, addr: usize) -> usize {
     (mode as usize) << 60
@@ -62,6 +107,71 @@ pub fn mstatus_read() -> usize {
     }
 }
 
+pub fn mie_set(bits: usize) {
+    unsafe {
+        asm!("csrs      mie, $0" ::"r"(bits));
+    }
+}
+
+pub fn mie_clear(bits: usize) {
+    unsafe {
+        asm!("csrc      mie, $0" ::"r"(bits));
+    }
+}
+
+pub fn mip_set(bits: usize) {
+    unsafe {
+        asm!("csrs      mip, $0" ::"r"(bits));
+    }
+}
+
+pub fn mip_clear(bits: usize) {
+    unsafe {
+        asm!("csrc      mip, $0" ::"r"(bits));
+    }
+}
+
+pub fn sstatus_write(val: usize) {
+    unsafe {
+        asm!("csrw      sstatus, $0" ::"r"(val));
+    }
+}
+
+pub fn sstatus_read() -> usize {
+    unsafe {
+        let rval;
+        asm!("csrr      $0, sstatus" :"=r"(rval));
+        rval
+    }
+}
+
+// Clear bits in sstatus and return what it was before
+pub fn sstatus_clear(bits: usize) -> usize {
+    unsafe {
+        let rval;
+        asm!("csrrc     $0, sstatus, $1" :"=r"(rval) :"r"(bits) :: "volatile");
+        rval
+    }
+}
+
+pub fn sstatus_set(bits: usize) {
+    unsafe {
+        asm!("csrs      sstatus, $0" ::"r"(bits) :: "volatile");
+    }
+}
+
+pub fn sip_set(bits: usize) {
+    unsafe {
+        asm!("csrs      sip, $0" ::"r"(bits));
+    }
+}
+
+pub fn sip_clear(bits: usize) {
+    unsafe {
+        asm!("csrc      sip, $0" ::"r"(bits));
+    }
+}
+
 pub fn stvec_write(val: usize) {
     unsafe {
         asm!("csrw      stvec, $0" ::"r"(val));
@@ -120,6 +230,14 @@ pub fn sscratch_swap(to: usize) -> usize {
     }
 }
 
+// Which hart we're on, from supervisor mode. mhartid is out of reach
+// here, but sscratch always points at a trap frame that knows: the one
+// we're running, or this hart's kernel trap frame before the first
+// context switch.
+pub fn current_hart() -> usize {
+    unsafe { (*(sscratch_read() as *const TrapFrame)).hartid }
+}
+
 pub fn sepc_write(val: usize) {
     unsafe {
         asm!("csrw      sepc, $0" ::"r"(val));
@@ -158,4 +276,69 @@ pub fn satp_fence_asid(asid: usize) {
     unsafe {
         asm!("sfence.vma    zero, $0" ::"r"(asid));
     }
-}
\ No newline at end of file
+}
+
+// The same, but for every address space, global mappings included
+pub fn satp_fence_all(vaddr: usize) {
+    unsafe {
+        asm!("sfence.vma    $0, zero" ::"r"(vaddr));
+    }
+}
+
+pub fn satp_fence_everything() {
+    unsafe {
+        asm!("sfence.vma    zero, zero" :::: "volatile");
+    }
+}
+
+// Make stores to instruction memory visible to this hart's fetches.
+pub fn fence_i() {
+    unsafe {
+        asm!("fence.i" :::: "volatile");
+    }
+}
+
+// Ask machine mode to program this hart's mtimecmp. Supervisor mode can't
+// clear the forwarded timer interrupt (STIP) itself, so this goes
+// through m_trap, which clears it and re-arms the machine timer.
+pub fn sbi_set_timer(when: u64) {
+    unsafe {
+        asm!("ecall" :: "{a0}"(when), "{a7}"(SBI_SET_TIMER) : "memory" : "volatile");
+    }
+}
+
+// Get the FP registers ready for frame before switching to it on hart.
+// Saving is done by s_trap_vector whenever FS is dirty, so the frame
+// always has the latest copy and we only need to load it if somebody
+// else's state is sitting in the registers.
+pub fn fp_prepare(frame: *mut TrapFrame, hart: usize) {
+    unsafe {
+        let f = &mut *frame;
+        if f.fp_state == FS_OFF {
+            return;
+        }
+        if FP_OWNER[hart] != frame as usize || f.fp_hart != hart {
+            fp_enable_and_load(frame, hart);
+        }
+        f.fp_state = FS_CLEAN;
+    }
+}
+
+// The first FP instruction a process runs traps as illegal because it
+// runs with FS off. Hand it the FPU with the (zeroed) state in its frame
+// rather than whatever the last process left in the registers.
+pub fn fp_first_use(frame: *mut TrapFrame, hart: usize) {
+    unsafe {
+        fp_enable_and_load(frame, hart);
+        (*frame).fp_state = FS_CLEAN;
+        // We return through s_trap_vector, which leaves sstatus alone.
+        sstatus_write((sstatus_read() & !(3 << FS_SHIFT)) | FS_CLEAN << FS_SHIFT);
+    }
+}
+
+unsafe fn fp_enable_and_load(frame: *mut TrapFrame, hart: usize) {
+    sstatus_write(sstatus_read() | FS_CLEAN << FS_SHIFT);
+    fp_load(frame as usize);
+    FP_OWNER[hart] = frame as usize;
+    (*frame).fp_hart = hart;
+}
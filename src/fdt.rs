@@ -0,0 +1,208 @@
+// Adam Short
+// 08/04/2020
+
+use crate::page::{zalloc, PAGE_SIZE};
+use alloc::vec::Vec;
+
+// The flattened device tree QEMU hands us in a1. It's a header, a
+// structure block of big-endian tokens and a block of property names.
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+// Our copy of the device tree, or 0 if we didn't get one
+static mut FDT: usize = 0;
+
+// A node in the tree. body is where its properties start, right after
+// the name.
+#[derive(Clone, Copy)]
+pub struct Node {
+    pub name: &'static str,
+    body: usize,
+}
+
+fn be32(addr: usize) -> u32 {
+    unsafe { u32::from_be((addr as *const u32).read_volatile()) }
+}
+
+fn align4(addr: usize) -> usize {
+    (addr + 3) & !3
+}
+
+fn cstr(addr: usize) -> &'static str {
+    let mut len = 0;
+    unsafe {
+        while (addr as *const u8).add(len).read() != 0 {
+            len += 1;
+        }
+        let bytes = core::slice::from_raw_parts(addr as *const u8, len);
+        core::str::from_utf8(bytes).unwrap_or("")
+    }
+}
+
+// QEMU puts the device tree at the top of RAM, which the page allocator
+// thinks is free. Copy it somewhere safe before anybody allocates that
+// far up. This runs in machine mode right after page::init.
+pub fn init(dtb: usize) -> bool {
+    if dtb == 0 || be32(dtb) != FDT_MAGIC {
+        warn!("No device tree at 0x{:x}.", dtb);
+        return false;
+    }
+    let size = be32(dtb + 4) as usize;
+    let copy = zalloc((size + PAGE_SIZE - 1) / PAGE_SIZE);
+    unsafe {
+        core::ptr::copy_nonoverlapping(dtb as *const u8, copy, size);
+        FDT = copy as usize;
+    }
+    true
+}
+
+pub fn is_present() -> bool {
+    unsafe { FDT != 0 }
+}
+
+fn header(offset: usize) -> usize {
+    unsafe { be32(FDT + offset) as usize }
+}
+
+// Go through the tokens of the node whose properties start at body.
+// on_prop gets every property of this node and on_child every direct
+// child. Returns the address right after the node's END_NODE.
+fn walk<P, C>(body: usize, mut on_prop: P, mut on_child: C) -> usize
+    where P: FnMut(&'static str, &'static [u8]),
+          C: FnMut(Node)
+{
+    let strings = unsafe { FDT } + header(12);
+    let mut addr = body;
+    loop {
+        match be32(addr) {
+            FDT_PROP => {
+                let len = be32(addr + 4) as usize;
+                let name = cstr(strings + be32(addr + 8) as usize);
+                let value = unsafe { core::slice::from_raw_parts((addr + 12) as *const u8, len) };
+                on_prop(name, value);
+                addr = align4(addr + 12 + len);
+            },
+            FDT_BEGIN_NODE => {
+                let name = cstr(addr + 4);
+                let child = Node { name, body: align4(addr + 4 + name.len() + 1) };
+                on_child(child);
+                addr = walk(child.body, |_, _| {}, |_| {});
+            },
+            FDT_NOP => addr += 4,
+            FDT_END_NODE => return addr + 4,
+            // FDT_END, or something we don't understand
+            _ => return addr,
+        }
+    }
+}
+
+pub fn root() -> Option<Node> {
+    if !is_present() {
+        return None;
+    }
+    let mut addr = unsafe { FDT } + header(8);
+    while be32(addr) == FDT_NOP {
+        addr += 4;
+    }
+    if be32(addr) != FDT_BEGIN_NODE {
+        return None;
+    }
+    let name = cstr(addr + 4);
+    Some(Node { name, body: align4(addr + 4 + name.len() + 1) })
+}
+
+// Find a node by its path, like "/cpus" or "/soc/uart@10000000". A
+// component without a unit address matches any, so "/memory" finds
+// "/memory@80000000".
+pub fn find_node(path: &str) -> Option<Node> {
+    let mut node = root()?;
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        node = node.children()
+                   .into_iter()
+                   .find(|c| c.name == component || c.name.split('@').next() == Some(component))?;
+    }
+    Some(node)
+}
+
+// Every node anywhere in the tree that lists compat in its compatible
+// property
+pub fn find_compatible(compat: &str) -> Vec<Node> {
+    let mut found = Vec::new();
+    let mut todo = Vec::new();
+    todo.extend(root());
+    while let Some(node) = todo.pop() {
+        if node.is_compatible(compat) {
+            found.push(node);
+        }
+        todo.extend(node.children());
+    }
+    found
+}
+
+impl Node {
+    pub fn children(&self) -> Vec<Node> {
+        let mut children = Vec::new();
+        walk(self.body, |_, _| {}, |c| children.push(c));
+        children
+    }
+
+    pub fn property(&self, name: &str) -> Option<&'static [u8]> {
+        let mut found = None;
+        walk(self.body, |n, v| if n == name && found.is_none() { found = Some(v) }, |_| {});
+        found
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        let v = self.property(name)?;
+        if v.len() < 4 {
+            return None;
+        }
+        Some(u32::from_be_bytes([v[0], v[1], v[2], v[3]]))
+    }
+
+    // A one or two cell number
+    pub fn prop_u64(&self, name: &str) -> Option<u64> {
+        let v = self.property(name)?;
+        match v.len() {
+            4 => self.prop_u32(name).map(|n| n as u64),
+            8 => {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(v);
+                Some(u64::from_be_bytes(bytes))
+            },
+            _ => None
+        }
+    }
+
+    pub fn prop_str(&self, name: &str) -> Option<&'static str> {
+        let v = self.property(name)?;
+        let end = v.iter().position(|&b| b == 0).unwrap_or(v.len());
+        core::str::from_utf8(&v[..end]).ok()
+    }
+
+    // compatible is a list of strings, most specific first.
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        match self.property("compatible") {
+            Some(v) => v.split(|&b| b == 0).any(|s| s == compat.as_bytes()),
+            None => false
+        }
+    }
+
+    // The first (address, size) pair in reg. QEMU's virt board uses two
+    // cells for both everywhere we care about.
+    pub fn reg(&self) -> Option<(usize, usize)> {
+        let v = self.property("reg")?;
+        if v.len() < 16 {
+            return None;
+        }
+        let mut addr = [0u8; 8];
+        let mut size = [0u8; 8];
+        addr.copy_from_slice(&v[0..8]);
+        size.copy_from_slice(&v[8..16]);
+        Some((u64::from_be_bytes(addr) as usize, u64::from_be_bytes(size) as usize))
+    }
+}
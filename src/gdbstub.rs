@@ -0,0 +1,761 @@
+// Adam Short
+// 08/05/2020
+
+use crate::cmdline::Param;
+use crate::console;
+use crate::cpu::{self, TrapFrame};
+use crate::ipi::{all, send_ipi, Ipi};
+use crate::kmem;
+use crate::page::{self, user_virt_to_phys, EntryBits, Table, PAGE_SIZE};
+use crate::process::{with_process_by_frame, with_process_by_pid, ProcessState, PROCESS_LIST};
+use crate::signal::{send, SIGCONT, SIGINT, SIGSTOP, SIGTRAP};
+use crate::sync::Spinlock;
+use crate::uart::Port;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+// A GDB remote serial protocol stub on one of the UARTs. Every process
+// is a thread, and memory goes through that process' page table, so
+// `target remote` sees what the kernel sees rather than what the
+// machine does. Stopping stops every process, like all-stop mode in
+// GDB: ^C or a breakpoint sends SIGSTOP to everything, and continuing
+// sends SIGCONT to what we stopped.
+//
+// Breakpoints are ebreaks written over the code. medeleg hands cause 3
+// to supervisor mode, so they arrive in s_trap, not m_trap. The code
+// processes run is the kernel's text mapped a second time, so a
+// breakpoint is in every process at once, and whichever one hits it
+// first is the one that stops. There's no single-step in hardware, so
+// `s` puts temporary breakpoints wherever the instruction can go next.
+
+// The longest packet we take, which we tell GDB in qSupported
+const PACKET_SIZE: usize = 0x1000;
+
+// ebreak, and c.ebreak for two-byte breakpoints
+const EBREAK: [u8; 4] = [0x73, 0x00, 0x10, 0x00];
+const C_EBREAK: [u8; 2] = [0x02, 0x90];
+
+// Where memory gets mapped in the kernel's page table while we write
+// to it. Code is mapped read-only everywhere else. It's the last page
+// below the top of the lower half in Sv39, which Sv48 has too.
+const POKE_ADDR: usize = 0x3f_ffff_f000;
+
+// Where the receiver is in a packet: $data#xx
+#[derive(Clone, Copy)]
+enum Rx {
+    Idle,
+    Data,
+    Checksum(u8, usize),
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    // Whose memory it's in, which needn't be the thread by the time it
+    // comes out
+    pid: u16,
+    addr: usize,
+    len: usize,
+    orig: [u8; 4],
+    // Put in by `s`, and taken out again at the next stop
+    temp: bool,
+}
+
+struct GdbState {
+    port: Option<&'static Port>,
+    rx: Rx,
+    packet: Vec<u8>,
+    // Set by the first packet, cleared by D and k
+    attached: bool,
+    // Everything is stopped, and these are the processes we stopped.
+    // Processes that were stopped already are left alone.
+    halted: bool,
+    stopped: Vec<u16>,
+    // Why we stopped, as a signal, and which process it happened to
+    stop_sig: usize,
+    stop_pid: u16,
+    // The thread from Hg, for registers and memory, and from Hc, for
+    // s. 0 means GDB didn't pick one.
+    thread: u16,
+    cont_thread: u16,
+    breakpoints: Vec<Breakpoint>,
+}
+
+static STATE: Spinlock<GdbState> = Spinlock::new(GdbState {
+    port: None,
+    rx: Rx::Idle,
+    packet: Vec::new(),
+    attached: false,
+    halted: false,
+    stopped: Vec::new(),
+    stop_sig: 0,
+    stop_pid: 0,
+    thread: 0,
+    cont_thread: 0,
+    breakpoints: Vec::new(),
+});
+
+// The console the stub takes over. gdb=off turns it off.
+static mut PORT_NAME: &str = "ttyS1";
+
+// Kernel parameters
+pub const PARAMS: [Param; 1] = [
+    Param { name: "gdb", set: param_gdb, help: "ttyS<n> or off" },
+];
+
+fn param_gdb(value: &'static str) -> bool {
+    match value {
+        "" => false,
+        "off" => { unsafe { PORT_NAME = ""; } true },
+        name => { unsafe { PORT_NAME = name; } true }
+    }
+}
+
+// Take over the port for the stub. Nothing else gets to print there,
+// since GDB would read it as a garbled packet. Runs in kmain, after
+// tty::init and before the UART interrupts are turned on.
+pub fn init() {
+    let name = unsafe { PORT_NAME };
+    if name.is_empty() {
+        return;
+    }
+    let port = match console::find_console(name).and_then(|c| c.port()) {
+        Some(port) => port,
+        None => { debug!("No {} for the GDB stub", name); return; }
+    };
+    if name == "ttyS0" {
+        warn!("Not putting the GDB stub on the console UART");
+        return;
+    }
+    console::set_active(name, false);
+    STATE.lock().port = Some(port);
+    port.set_input(input);
+    info!("GDB stub listening on {}", name);
+}
+
+// ///////////////////////////////////
+// / PACKETS
+// ///////////////////////////////////
+
+// Take one byte from the port. This runs in the UART's interrupt, which
+// is where every command is carried out.
+fn input(c: u8) {
+    let mut state = STATE.lock();
+    match state.rx {
+        Rx::Idle => match c {
+            b'$' => {
+                state.packet.clear();
+                state.rx = Rx::Data;
+            },
+            // ^C, outside of a packet
+            0x03 => {
+                if state.attached && !state.halted {
+                    let pid = stop_all(&mut state, 0);
+                    stopped(&mut state, pid, SIGINT);
+                }
+            },
+            // Acks from GDB, which we don't keep anything around for
+            _ => {}
+        },
+        Rx::Data => {
+            if c == b'#' {
+                state.rx = Rx::Checksum(0, 0);
+            }
+            else if state.packet.len() < PACKET_SIZE {
+                state.packet.push(c);
+            }
+        },
+        Rx::Checksum(sum, n) => {
+            let sum = sum << 4 | hex_digit(c).unwrap_or(0);
+            if n == 0 {
+                state.rx = Rx::Checksum(sum, 1);
+                return;
+            }
+            state.rx = Rx::Idle;
+            let port = match state.port {
+                Some(port) => port,
+                None => return
+            };
+            let packet = core::mem::replace(&mut state.packet, Vec::new());
+            if checksum(&packet) != sum {
+                port.write_str_sync("-");
+                return;
+            }
+            port.write_str_sync("+");
+            state.attached = true;
+            if let Some(reply) = command(&mut state, &packet) {
+                reply_packet(&state, &reply);
+            }
+        }
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn reply_packet(state: &GdbState, data: &str) {
+    if let Some(port) = state.port {
+        port.write_str_sync(&format!("${}#{:02x}", data, checksum(data.as_bytes())));
+    }
+}
+
+// Carry out one packet. Anything we don't know gets an empty reply,
+// which tells GDB it isn't supported. c and s don't get a reply until
+// something stops.
+fn command(state: &mut GdbState, packet: &[u8]) -> Option<String> {
+    let (&cmd, args) = match packet.split_first() {
+        Some(split) => split,
+        None => return Some(String::new())
+    };
+    let reply = match cmd {
+        b'?' => {
+            // GDB expects everything to be stopped once it's connected.
+            if !state.halted {
+                let pid = stop_all(state, 0);
+                state.stop_pid = pid;
+                state.stop_sig = SIGTRAP;
+            }
+            stop_reply(state)
+        },
+        b'q' => query(state, args),
+        b'H' => {
+            let pid = args.get(1..).and_then(parse_thread);
+            match (args.first(), pid) {
+                (Some(b'g'), Some(pid)) => { state.thread = pid; ok() },
+                (Some(b'c'), Some(pid)) => { state.cont_thread = pid; ok() },
+                _ => error()
+            }
+        },
+        b'T' => {
+            match parse_thread(args).filter(|&pid| is_alive(pid)) {
+                Some(_) => ok(),
+                None => error()
+            }
+        },
+        b'g' => match frame_of(thread(state)) {
+            Some(frame) => {
+                let frame = unsafe { &*frame };
+                let mut out = String::new();
+                for i in 0..32 {
+                    push_hex(&mut out, &reg(frame, i).to_le_bytes());
+                }
+                push_hex(&mut out, &frame.pc.to_le_bytes());
+                out
+            },
+            None => error()
+        },
+        b'G' => match (frame_of(thread(state)), decode_hex(args)) {
+            (Some(frame), Some(bytes)) if bytes.len() >= 33 * 8 => {
+                let frame = unsafe { &mut *frame };
+                for i in 1..33 {
+                    set_reg(frame, i, word(&bytes[i * 8..]));
+                }
+                ok()
+            },
+            _ => error()
+        },
+        b'p' => match (frame_of(thread(state)), parse_hex(args)) {
+            (Some(frame), Some(n)) if n <= 64 => {
+                let mut out = String::new();
+                push_hex(&mut out, &reg(unsafe { &*frame }, n).to_le_bytes());
+                out
+            },
+            _ => error()
+        },
+        b'P' => {
+            let (n, value) = split_at(args, b'=');
+            match (frame_of(thread(state)), parse_hex(n), decode_hex(value)) {
+                (Some(frame), Some(n), Some(bytes)) if n <= 64 && bytes.len() == 8 => {
+                    set_reg(unsafe { &mut *frame }, n, word(&bytes));
+                    ok()
+                },
+                _ => error()
+            }
+        },
+        b'm' => {
+            let (addr, len) = split_at(args, b',');
+            match (parse_hex(addr), parse_hex(len)) {
+                (Some(addr), Some(len)) => {
+                    let mut buf = Vec::new();
+                    buf.resize(len.min(PACKET_SIZE / 2), 0);
+                    if read_memory(thread(state), addr, &mut buf) {
+                        let mut out = String::new();
+                        push_hex(&mut out, &buf);
+                        out
+                    }
+                    else {
+                        error()
+                    }
+                },
+                _ => error()
+            }
+        },
+        b'M' => {
+            let (addr, rest) = split_at(args, b',');
+            let (len, data) = split_at(rest, b':');
+            match (parse_hex(addr), parse_hex(len), decode_hex(data)) {
+                (Some(addr), Some(len), Some(bytes)) if bytes.len() == len => {
+                    if write_memory(thread(state), addr, &bytes) { ok() } else { error() }
+                },
+                _ => error()
+            }
+        },
+        b'Z' | b'z' => {
+            let mut fields = args.split(|&b| b == b',');
+            let kind = fields.next();
+            let addr = fields.next().and_then(parse_hex);
+            let len = fields.next().and_then(parse_hex);
+            match (kind, addr, len) {
+                // Software breakpoints only
+                (Some(b"0"), Some(addr), Some(len)) if len == 2 || len == 4 => {
+                    let done = if cmd == b'Z' {
+                        insert_breakpoint(state, addr, len, false)
+                    }
+                    else {
+                        remove_breakpoint(state, addr)
+                    };
+                    if done { ok() } else { error() }
+                },
+                (Some(b"0"), _, _) => error(),
+                _ => String::new()
+            }
+        },
+        b'c' | b's' => {
+            let pid = if state.cont_thread != 0 { state.cont_thread } else { thread(state) };
+            if let (Some(addr), Some(frame)) = (parse_hex(args), frame_of(pid)) {
+                unsafe { (*frame).pc = addr; }
+            }
+            if cmd == b's' && !step(state, pid) {
+                return Some(error());
+            }
+            resume_all(state);
+            return None;
+        },
+        // Detach, or kill, which for us is the same thing: nothing
+        // belongs to GDB, so everything just carries on without it.
+        b'D' | b'k' => {
+            detach(state);
+            if cmd == b'k' {
+                return None;
+            }
+            ok()
+        },
+        _ => String::new()
+    };
+    Some(reply)
+}
+
+fn query(state: &mut GdbState, args: &[u8]) -> String {
+    let (name, rest) = split_at(args, b',');
+    let name = split_at(name, b':').0;
+    match name {
+        b"Supported" => format!("PacketSize={:x}", PACKET_SIZE),
+        b"Attached" => String::from("1"),
+        b"C" => format!("QC{:x}", thread(state)),
+        b"fThreadInfo" => {
+            let pids = live_pids();
+            if pids.is_empty() {
+                return String::from("l");
+            }
+            let mut out = String::from("m");
+            for (i, pid) in pids.iter().enumerate() {
+                let _ = write!(out, "{}{:x}", if i == 0 { "" } else { "," }, pid);
+            }
+            out
+        },
+        b"sThreadInfo" => String::from("l"),
+        b"ThreadExtraInfo" => {
+            let pid = parse_hex(rest).filter(|&pid| pid <= u16::max_value() as usize);
+            let info = pid.and_then(|pid| with_process_by_pid(pid as u16, |p| {
+                let what = match p.get_state() {
+                    ProcessState::Running => "running",
+                    ProcessState::Sleeping => "sleeping",
+                    ProcessState::Waiting => "waiting",
+                    ProcessState::Stopped => "stopped",
+                    ProcessState::Dead => "dead",
+                };
+                format!("{} on hart {}", what, p.get_hart())
+            }));
+            match info {
+                Some(info) => {
+                    let mut out = String::new();
+                    push_hex(&mut out, info.as_bytes());
+                    out
+                },
+                None => error()
+            }
+        },
+        _ => String::new()
+    }
+}
+
+fn ok() -> String {
+    String::from("OK")
+}
+
+fn error() -> String {
+    String::from("E01")
+}
+
+fn stop_reply(state: &GdbState) -> String {
+    if state.stop_pid == 0 {
+        format!("S{:02x}", state.stop_sig)
+    }
+    else {
+        format!("T{:02x}thread:{:x};", state.stop_sig, state.stop_pid)
+    }
+}
+
+// ///////////////////////////////////
+// / STOPPING AND STARTING
+// ///////////////////////////////////
+
+// Stop every process that isn't stopped already. Returns the PID to
+// report the stop against: first, if it's given, or otherwise the first
+// process there is.
+fn stop_all(state: &mut GdbState, first: u16) -> u16 {
+    let mut pid = first;
+    if let Some(pl) = PROCESS_LIST.lock().as_mut() {
+        for prc in pl.iter_mut() {
+            match prc.get_state() {
+                ProcessState::Dead => continue,
+                ProcessState::Stopped => {},
+                _ => {
+                    send(prc, SIGSTOP);
+                    state.stopped.push(prc.get_pid());
+                }
+            }
+            if pid == 0 {
+                pid = prc.get_pid();
+            }
+        }
+    }
+    state.halted = true;
+    pid
+}
+
+// Let everything we stopped go again
+fn resume_all(state: &mut GdbState) {
+    for pid in core::mem::replace(&mut state.stopped, Vec::new()) {
+        with_process_by_pid(pid, |p| send(p, SIGCONT));
+    }
+    state.halted = false;
+}
+
+// Tell GDB that pid stopped with sig. The temporary breakpoints from a
+// step have done their job by now.
+fn stopped(state: &mut GdbState, pid: u16, sig: usize) {
+    let temps: Vec<usize> = state.breakpoints.iter().filter(|b| b.temp).map(|b| b.addr).collect();
+    for addr in temps {
+        remove_breakpoint(state, addr);
+    }
+    state.stop_pid = pid;
+    state.stop_sig = sig;
+    state.thread = pid;
+    state.cont_thread = 0;
+    let reply = stop_reply(state);
+    reply_packet(state, &reply);
+}
+
+fn detach(state: &mut GdbState) {
+    let addrs: Vec<usize> = state.breakpoints.iter().map(|b| b.addr).collect();
+    for addr in addrs {
+        remove_breakpoint(state, addr);
+    }
+    resume_all(state);
+    state.attached = false;
+    state.thread = 0;
+    state.cont_thread = 0;
+}
+
+// A process ran into an ebreak. If GDB is there, everything stops and
+// it's told which process it was. Otherwise the process gets SIGTRAP,
+// which kills it. Either way, the caller has to reschedule.
+pub fn breakpoint(frame: *mut TrapFrame) {
+    let mut state = STATE.lock();
+    if !state.attached {
+        drop(state);
+        with_process_by_frame(frame, |p| send(p, SIGTRAP));
+        return;
+    }
+    let pid = match with_process_by_frame(frame, |p| p.get_pid()) {
+        Some(pid) => pid,
+        None => return
+    };
+    // If somebody else stopped first, this one was on its way into the
+    // ebreak at the time. GDB has heard about the stop already, and this
+    // one runs into the ebreak again once it's continued.
+    let reported = state.halted;
+    stop_all(&mut state, pid);
+    if !reported {
+        stopped(&mut state, pid, SIGTRAP);
+    }
+}
+
+// ///////////////////////////////////
+// / THREADS AND REGISTERS
+// ///////////////////////////////////
+
+// The thread registers and memory come from: Hg's, or the one that
+// stopped
+fn thread(state: &GdbState) -> u16 {
+    if state.thread != 0 { state.thread } else { state.stop_pid }
+}
+
+fn live_pids() -> Vec<u16> {
+    PROCESS_LIST.lock()
+                .as_ref()
+                .map(|pl| pl.iter()
+                            .filter(|p| match p.get_state() { ProcessState::Dead => false, _ => true })
+                            .map(|p| p.get_pid())
+                            .collect())
+                .unwrap_or_else(Vec::new)
+}
+
+fn is_alive(pid: u16) -> bool {
+    live_pids().contains(&pid)
+}
+
+// A stopped process doesn't touch its frame, so it's fine to use once
+// we've let go of the process list.
+fn frame_of(pid: u16) -> Option<*mut TrapFrame> {
+    with_process_by_pid(pid, |p| p.get_frame_address() as *mut TrapFrame)
+}
+
+// GDB numbers x0-x31 as 0-31, then the pc, then f0-f31.
+fn reg(frame: &TrapFrame, n: usize) -> usize {
+    match n {
+        0 => 0,
+        1..=31 => frame.regs[n],
+        32 => frame.pc,
+        _ => frame.fregs[n - 33]
+    }
+}
+
+fn set_reg(frame: &mut TrapFrame, n: usize, value: usize) {
+    match n {
+        0 => {},
+        1..=31 => frame.regs[n] = value,
+        32 => frame.pc = value,
+        _ => frame.fregs[n - 33] = value
+    }
+}
+
+// ///////////////////////////////////
+// / MEMORY
+// ///////////////////////////////////
+
+fn read_memory(pid: u16, addr: usize, buf: &mut [u8]) -> bool {
+    with_process_by_pid(pid, |p| p.copy_from_user(addr, buf)).unwrap_or(false)
+}
+
+// Write to anything the process can read, which is how breakpoints get
+// into code. Each page is mapped writable in the kernel's page table
+// for as long as it takes.
+fn write_memory(pid: u16, addr: usize, data: &[u8]) -> bool {
+    let mut chunks = Vec::new();
+    let found = with_process_by_pid(pid, |p| {
+        let pt = unsafe { &*(p.get_table_address() as *const Table) };
+        let mut done = 0;
+        while done < data.len() {
+            let vaddr = addr + done;
+            let paddr = match user_virt_to_phys(pt, vaddr, false) {
+                Some(paddr) => paddr,
+                None => return false
+            };
+            let chunk = (PAGE_SIZE - vaddr % PAGE_SIZE).min(data.len() - done);
+            chunks.push((paddr, done, chunk));
+            done += chunk;
+        }
+        true
+    });
+    if found != Some(true) {
+        return false;
+    }
+
+    let root = unsafe { &mut *kmem::get_page_table() };
+    let bits = EntryBits::ReadWrite.val() | EntryBits::Access.val() | EntryBits::Dirty.val();
+    for (paddr, off, len) in chunks {
+        page::map(root, POKE_ADDR, paddr & !(PAGE_SIZE - 1), bits, 0);
+        cpu::satp_fence(POKE_ADDR, 0);
+        unsafe {
+            core::ptr::copy_nonoverlapping(data[off..].as_ptr(),
+                                           (POKE_ADDR + paddr % PAGE_SIZE) as *mut u8,
+                                           len);
+        }
+        page::unmap_range(root, POKE_ADDR, PAGE_SIZE, 0);
+    }
+    // Every hart could have the old code in its instruction cache.
+    send_ipi(all(), Ipi::Call(sync_icache, 0));
+    true
+}
+
+fn sync_icache(_: usize) {
+    cpu::fence_i();
+}
+
+// ///////////////////////////////////
+// / BREAKPOINTS AND STEPPING
+// ///////////////////////////////////
+
+fn insert_breakpoint(state: &mut GdbState, addr: usize, len: usize, temp: bool) -> bool {
+    if let Some(bp) = state.breakpoints.iter_mut().find(|b| b.addr == addr) {
+        // GDB asking for one where a step put one makes it stay.
+        bp.temp = bp.temp && temp;
+        return true;
+    }
+    let pid = thread(state);
+    let mut orig = [0u8; 4];
+    if !read_memory(pid, addr, &mut orig[..len]) {
+        return false;
+    }
+    let ebreak: &[u8] = if len == 2 { &C_EBREAK } else { &EBREAK };
+    if !write_memory(pid, addr, ebreak) {
+        return false;
+    }
+    state.breakpoints.push(Breakpoint { pid, addr, len, orig, temp });
+    true
+}
+
+fn remove_breakpoint(state: &mut GdbState, addr: usize) -> bool {
+    let i = match state.breakpoints.iter().position(|b| b.addr == addr) {
+        Some(i) => i,
+        None => return false
+    };
+    let bp = state.breakpoints.remove(i);
+    write_memory(bp.pid, bp.addr, &bp.orig[..bp.len])
+}
+
+// Put a temporary breakpoint everywhere pid's next instruction can go.
+// c.ebreak fits over anything, so that's what they all are.
+fn step(state: &mut GdbState, pid: u16) -> bool {
+    let frame = match frame_of(pid) {
+        Some(frame) => unsafe { &*frame },
+        None => return false
+    };
+    let targets = match next_pcs(pid, frame) {
+        Some(targets) => targets,
+        None => return false
+    };
+    state.thread = pid;
+    targets.into_iter().all(|addr| insert_breakpoint(state, addr, 2, true))
+}
+
+fn sext(value: usize, bits: u32) -> usize {
+    ((value << (64 - bits)) as isize >> (64 - bits)) as usize
+}
+
+// Decode the instruction at the pc, far enough to know where control
+// goes after it. Branches can go either way, so they give both.
+fn next_pcs(pid: u16, frame: &TrapFrame) -> Option<Vec<usize>> {
+    let pc = frame.pc;
+    let mut half = [0u8; 2];
+    if !read_memory(pid, pc, &mut half) {
+        return None;
+    }
+    let c = u16::from_le_bytes(half) as usize;
+
+    if c & 3 != 3 {
+        let next = pc + 2;
+        let rs1 = c >> 7 & 0x1f;
+        let rs2 = c >> 2 & 0x1f;
+        let targets = match (c & 3, c >> 13 & 7) {
+            // c.j
+            (1, 5) => {
+                let imm = (c >> 12 & 1) << 11 | (c >> 11 & 1) << 4 | (c >> 9 & 3) << 8
+                          | (c >> 8 & 1) << 10 | (c >> 7 & 1) << 6 | (c >> 6 & 1) << 7
+                          | (c >> 3 & 7) << 1 | (c >> 2 & 1) << 5;
+                vec![pc.wrapping_add(sext(imm, 12))]
+            },
+            // c.beqz and c.bnez
+            (1, 6) | (1, 7) => {
+                let imm = (c >> 12 & 1) << 8 | (c >> 10 & 3) << 3 | (c >> 5 & 3) << 6
+                          | (c >> 3 & 3) << 1 | (c >> 2 & 1) << 5;
+                vec![next, pc.wrapping_add(sext(imm, 9))]
+            },
+            // c.jr and c.jalr
+            (2, 4) if rs1 != 0 && rs2 == 0 => vec![frame.regs[rs1] & !1],
+            _ => vec![next]
+        };
+        return Some(targets);
+    }
+
+    let mut word = [0u8; 4];
+    if !read_memory(pid, pc, &mut word) {
+        return None;
+    }
+    let insn = u32::from_le_bytes(word) as usize;
+    let next = pc + 4;
+    let rs1 = insn >> 15 & 0x1f;
+    let targets = match insn & 0x7f {
+        // jal
+        0x6f => {
+            let imm = (insn >> 31 & 1) << 20 | (insn >> 21 & 0x3ff) << 1
+                      | (insn >> 20 & 1) << 11 | (insn >> 12 & 0xff) << 12;
+            vec![pc.wrapping_add(sext(imm, 21))]
+        },
+        // jalr
+        0x67 => vec![reg(frame, rs1).wrapping_add(sext(insn >> 20, 12)) & !1],
+        // Branches
+        0x63 => {
+            let imm = (insn >> 31 & 1) << 12 | (insn >> 25 & 0x3f) << 5
+                      | (insn >> 8 & 0xf) << 1 | (insn >> 7 & 1) << 11;
+            vec![next, pc.wrapping_add(sext(imm, 13))]
+        },
+        _ => vec![next]
+    };
+    Some(targets)
+}
+
+// ///////////////////////////////////
+// / HEX
+// ///////////////////////////////////
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+fn parse_hex(s: &[u8]) -> Option<usize> {
+    core::str::from_utf8(s).ok().and_then(|s| usize::from_str_radix(s, 16).ok())
+}
+
+// A thread ID in H and T. -1 is all of them and 0 is any, which both
+// mean we pick. Anything past 16 bits isn't a pid, rather than being
+// some other process' once truncated.
+fn parse_thread(s: &[u8]) -> Option<u16> {
+    if s == b"-1" {
+        return Some(0);
+    }
+    parse_hex(s).filter(|&pid| pid <= u16::max_value() as usize).map(|pid| pid as u16)
+}
+
+fn decode_hex(s: &[u8]) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    s.chunks(2)
+     .map(|pair| Some(hex_digit(pair[0])? << 4 | hex_digit(pair[1])?))
+     .collect()
+}
+
+fn push_hex(out: &mut String, bytes: &[u8]) {
+    for b in bytes {
+        let _ = write!(out, "{:02x}", b);
+    }
+}
+
+fn word(bytes: &[u8]) -> usize {
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&bytes[..8]);
+    usize::from_le_bytes(word)
+}
+
+fn split_at(s: &[u8], sep: u8) -> (&[u8], &[u8]) {
+    match s.iter().position(|&b| b == sep) {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, &[])
+    }
+}
@@ -0,0 +1,141 @@
+// Adam Short
+// 08/04/2020
+
+use crate::cpu::{current_hart, satp_fence, satp_fence_all, satp_fence_asid, satp_fence_everything,
+                 sip_set, MAX_HARTS, SSIP};
+use crate::page::PAGE_SIZE;
+use crate::sched::is_online;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+// Writing 1 to a hart's word here raises its machine software interrupt.
+const CLINT_MSIP: usize = 0x0200_0000;
+
+// An asid for TlbFlush that means every address space, including the
+// kernel's global mappings
+pub const ALL_ASIDS: usize = usize::max_value();
+
+// Past this many pages, flushing the whole address space is cheaper
+// than going one page at a time.
+const FLUSH_ALL_PAGES: usize = 64;
+
+// What one hart can ask another to do
+#[derive(Clone, Copy)]
+pub enum Ipi {
+    // Go through the scheduler as soon as supervisor interrupts are on.
+    Reschedule,
+    // Drop any TLB entries for [vaddr, vaddr + len) in address space asid.
+    TlbFlush { vaddr: usize, len: usize, asid: usize },
+    // Run func(arg). This happens in machine mode on the trap stack,
+    // so it has to be short and only touch kernel memory.
+    Call(fn(usize), usize),
+}
+
+// Every sender has its own slot in each hart's mailbox, so nobody has to
+// take a lock to send. The receiver clears PENDING once it has handled
+// the message, which is how the sender knows it's done.
+static mut MESSAGES: [[Ipi; MAX_HARTS]; MAX_HARTS] = [[Ipi::Reschedule; MAX_HARTS]; MAX_HARTS];
+static mut PENDING: [[bool; MAX_HARTS]; MAX_HARTS] = [[false; MAX_HARTS]; MAX_HARTS];
+
+// Send kind to every hart in hart_mask (bit n is hart n) and wait until
+// all of them have handled it. Harts that aren't online are skipped, and
+// if we're in the mask ourselves we just do it here.
+// The other harts take this in machine mode, which interrupts them even
+// when the kernel has supervisor interrupts off. So it's fine to wait
+// here while holding a spinlock they might want.
+pub fn send_ipi(hart_mask: usize, kind: Ipi) {
+    let me = current_hart();
+    let targets = (0..MAX_HARTS).filter(|&h| hart_mask & (1 << h) != 0);
+
+    for hart in targets.clone() {
+        if hart == me {
+            run(kind);
+        }
+        else if is_online(hart) {
+            unsafe {
+                MESSAGES[hart][me] = kind;
+                // The message has to be there before anybody sees it
+                // pending.
+                fence(Ordering::Release);
+                write_volatile(&mut PENDING[hart][me], true);
+                (CLINT_MSIP as *mut u32).add(hart).write_volatile(1);
+            }
+        }
+    }
+
+    for hart in targets.filter(|&h| h != me) {
+        unsafe {
+            while read_volatile(&PENDING[hart][me]) {
+                core::sync::atomic::spin_loop_hint();
+            }
+        }
+    }
+    fence(Ordering::Acquire);
+}
+
+// Every hart but us
+pub fn others() -> usize {
+    ((1 << MAX_HARTS) - 1) & !(1 << current_hart())
+}
+
+pub fn all() -> usize {
+    (1 << MAX_HARTS) - 1
+}
+
+// Change a page table, then call this before the memory that was mapped
+// can be used for anything else. Any hart might have run the process and
+// still have the old translations cached.
+// Until the boot hart starts the others, it's the only one with a TLB to
+// flush. That's also when kinit builds the kernel's table in machine
+// mode, before sscratch is set up for current_hart(), so don't go near
+// send_ipi then.
+pub fn flush_tlb_range(vaddr: usize, len: usize, asid: usize) {
+    let kind = Ipi::TlbFlush { vaddr, len, asid };
+    if (1..MAX_HARTS).any(is_online) {
+        send_ipi(all(), kind);
+    }
+    else {
+        run(kind);
+    }
+}
+
+// Flush everything for asid, which we need when page tables themselves
+// have been freed.
+pub fn flush_tlb(asid: usize) {
+    flush_tlb_range(0, usize::max_value(), asid);
+}
+
+// Called from m_trap's machine software interrupt arm. Handle every
+// message waiting for this hart and let the senders know.
+pub fn handle(hart: usize) {
+    for sender in 0..MAX_HARTS {
+        unsafe {
+            if read_volatile(&PENDING[hart][sender]) {
+                fence(Ordering::Acquire);
+                run(MESSAGES[hart][sender]);
+                fence(Ordering::Release);
+                write_volatile(&mut PENDING[hart][sender], false);
+            }
+        }
+    }
+}
+
+fn run(kind: Ipi) {
+    match kind {
+        // SSIP is delegated, so this works from machine mode too.
+        Ipi::Reschedule => sip_set(SSIP),
+        Ipi::TlbFlush { vaddr, len, asid } => {
+            if len / PAGE_SIZE > FLUSH_ALL_PAGES {
+                if asid == ALL_ASIDS { satp_fence_everything() } else { satp_fence_asid(asid) }
+            }
+            else {
+                let mut addr = vaddr & !(PAGE_SIZE - 1);
+                while addr < vaddr + len {
+                    if asid == ALL_ASIDS { satp_fence_all(addr) } else { satp_fence(addr, asid) }
+                    addr += PAGE_SIZE;
+                }
+            }
+        },
+        Ipi::Call(func, arg) => func(arg),
+    }
+}
@@ -0,0 +1,113 @@
+// Adam Short
+// 08/04/2020
+
+use crate::plic::{self, Context, MAX_IRQS};
+use crate::sync::Spinlock;
+
+// Drivers get the number of the interrupt they're handling, so one
+// handler can serve several devices.
+pub type IrqHandler = fn(u32);
+
+#[derive(Clone, Copy)]
+struct IrqEntry {
+    handler: Option<IrqHandler>,
+    count: usize,
+}
+
+static IRQS: Spinlock<[IrqEntry; MAX_IRQS]> =
+    Spinlock::new([IrqEntry { handler: None, count: 0 }; MAX_IRQS]);
+// Claims that came back empty, or for a source nobody registered
+static SPURIOUS: Spinlock<usize> = Spinlock::new(0);
+
+// Hook a handler up to an interrupt source and turn the source on.
+// Each source has one handler, so this fails if there already is one or
+// the PLIC doesn't have that source.
+pub fn register_irq(irq: u32, handler: IrqHandler, priority: u8) -> bool {
+    if irq == 0 || irq as usize > plic::num_sources() || priority == 0 {
+        return false;
+    }
+    {
+        let mut irqs = IRQS.lock();
+        if irqs[irq as usize].handler.is_some() {
+            return false;
+        }
+        irqs[irq as usize] = IrqEntry { handler: Some(handler), count: 0 };
+    }
+    plic::set_priority(irq, priority);
+    plic::enable(irq);
+    true
+}
+
+pub fn unregister_irq(irq: u32) -> bool {
+    if irq == 0 || irq as usize >= MAX_IRQS {
+        return false;
+    }
+    plic::disable(irq);
+    plic::set_priority(irq, 0);
+    IRQS.lock()[irq as usize].handler.take().is_some()
+}
+
+// Take every interrupt the PLIC has for this hart, run its handler and
+// tell the PLIC we're done with it. s_trap calls this for cause 9.
+pub fn handle(hart: usize) {
+    let context = Context::supervisor(hart);
+    let mut claimed = false;
+    while let Some(irq) = context.claim() {
+        claimed = true;
+        // Don't hold the table while the handler runs: handlers can
+        // take their own locks and can register other interrupts.
+        let handler = {
+            let mut irqs = IRQS.lock();
+            let entry = &mut irqs[irq as usize];
+            entry.count += 1;
+            entry.handler
+        };
+        match handler {
+            Some(handler) => handler(irq),
+            None => spurious(irq),
+        }
+        context.complete(irq);
+    }
+    if !claimed {
+        // Another hart got there first, or the source went quiet
+        // before we claimed it.
+        *SPURIOUS.lock() += 1;
+    }
+}
+
+// A source with no handler shouldn't be on, so turn it off before it
+// floods us.
+fn spurious(irq: u32) {
+    let first = {
+        let mut spurious = SPURIOUS.lock();
+        *spurious += 1;
+        *spurious == 1
+    };
+    if first {
+        warn!("Spurious interrupt {} with no handler, disabling it", irq);
+    }
+    plic::disable(irq);
+}
+
+pub fn irq_count(irq: u32) -> usize {
+    if irq as usize >= MAX_IRQS {
+        return 0;
+    }
+    IRQS.lock()[irq as usize].count
+}
+
+pub fn spurious_count() -> usize {
+    *SPURIOUS.lock()
+}
+
+pub fn print_irq_stats() {
+    println!("IRQ   COUNT");
+    let irqs = IRQS.lock();
+    for irq in 1..=plic::num_sources() {
+        let entry = &irqs[irq];
+        if entry.handler.is_some() || entry.count != 0 {
+            println!("{:<5} {}", irq, entry.count);
+        }
+    }
+    println!("Spurious: {}", *SPURIOUS.lock());
+}
@@ -2,6 +2,7 @@
 // 08/02/2020
 
 use crate::page::{align_val, zalloc, Table, PAGE_SIZE};
+use crate::sync::Spinlock;
 use core::alloc::{GlobalAlloc, Layout};
 use core::mem::size_of;
 use core::ptr::null_mut;
@@ -53,12 +54,18 @@ impl AllocList {
 
 }
 
-static mut KMEM_HEAD: *mut AllocList = null_mut();
+// The first chunk of the kernel heap. Every hart allocates from here,
+// so the lock covers the whole allocation list, not just the pointer.
+struct KmemHead(*mut AllocList);
+unsafe impl Send for KmemHead {}
+
+static KMEM_HEAD: Spinlock<KmemHead> = Spinlock::new(KmemHead(null_mut()));
+// These two are only written by init, before anybody else runs.
 static mut KMEM_ALLOC: usize = 0;
 static mut KMEM_PAGE_TABLE: *mut Table = null_mut();
 
 pub fn get_head() -> *mut u8 {
-    unsafe { KMEM_HEAD as *mut u8 }
+    KMEM_HEAD.lock().0 as *mut u8
 }
 
 pub fn get_page_table() -> *mut Table {
@@ -74,9 +81,10 @@ pub fn init() {
         let k_alloc = zalloc(64);
         assert!(!k_alloc.is_null());
         KMEM_ALLOC = 64;
-        KMEM_HEAD = k_alloc as *mut AllocList;
-        (*KMEM_HEAD).set_free();
-        (*KMEM_HEAD).set_size(KMEM_ALLOC * PAGE_SIZE);
+        let head = k_alloc as *mut AllocList;
+        (*head).set_free();
+        (*head).set_size(KMEM_ALLOC * PAGE_SIZE);
+        KMEM_HEAD.lock().0 = head;
         KMEM_PAGE_TABLE = zalloc(1) as *mut Table;
     }
 }
@@ -96,8 +104,9 @@ pub fn kzmalloc(sz: usize) -> *mut u8 {
 pub fn kmalloc(sz: usize) -> *mut u8 {
     unsafe {
         let size = align_val(sz, 3) + size_of::<AllocList>();
-        let mut head = KMEM_HEAD;
-        let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE) as *mut AllocList;
+        let guard = KMEM_HEAD.lock();
+        let mut head = guard.0;
+        let tail = (guard.0 as *mut u8).add(KMEM_ALLOC * PAGE_SIZE) as *mut AllocList;
 
         while head < tail {
             if (*head).is_free() && size <= (*head).get_size() {
@@ -127,12 +136,13 @@ pub fn kmalloc(sz: usize) -> *mut u8 {
 pub fn kfree(ptr: *mut u8) {
     unsafe {
         if !ptr.is_null() {
+            let guard = KMEM_HEAD.lock();
             let p = (ptr as *mut AllocList).offset(-1);
             
             if (*p).is_taken() {
                 (*p).set_free();
             }
-            coalesce();
+            coalesce_from(guard.0);
         }
     }
 }
@@ -141,9 +151,14 @@ pub fn kfree(ptr: *mut u8) {
 // Coalesce the freed pages into one chunk
 ////////////////////////////////////////////////////////////////////////////
 pub fn coalesce() {
+    coalesce_from(KMEM_HEAD.lock().0);
+}
+
+// The caller holds the KMEM_HEAD lock.
+fn coalesce_from(start: *mut AllocList) {
     unsafe {
-        let mut head = KMEM_HEAD;
-        let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE) as *mut AllocList;
+        let mut head = start;
+        let tail = (start as *mut u8).add(KMEM_ALLOC * PAGE_SIZE) as *mut AllocList;
 
         while head < tail {
             let next = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
@@ -169,8 +184,9 @@ pub fn coalesce() {
 ////////////////////////////////////////////////////////////////////////////
 pub fn print_table() {
     unsafe {
-        let mut head = KMEM_HEAD;
-        let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE) as *mut AllocList;
+        let guard = KMEM_HEAD.lock();
+        let mut head = guard.0;
+        let tail = (guard.0 as *mut u8).add(KMEM_ALLOC * PAGE_SIZE) as *mut AllocList;
 
         while head < tail {
             println!("{:p}: Length = {:<10} Taken = {}", head, (*head).get_size(), (*head).is_taken());
@@ -21,8 +21,7 @@ extern crate alloc;
 macro_rules! print
 {
   ($($args:tt)+) => ({
-      use core::fmt::Write;
-      let _ = write!(crate::uart::Uart::new(0x1000_0000), $($args)+);
+      crate::console::_print(format_args!($($args)+));
       });
 }
 #[macro_export]
@@ -39,6 +38,44 @@ macro_rules! println
       });
 }
 
+// Kernel log messages, which go through crate::log. Each one is tagged
+// with the module it came from, so it can be filtered per module.
+#[macro_export]
+macro_rules! error
+{
+  ($($args:tt)+) => ({
+      crate::log::log(crate::log::Level::Error, module_path!(), format_args!($($args)+));
+      });
+}
+#[macro_export]
+macro_rules! warn
+{
+  ($($args:tt)+) => ({
+      crate::log::log(crate::log::Level::Warn, module_path!(), format_args!($($args)+));
+      });
+}
+#[macro_export]
+macro_rules! info
+{
+  ($($args:tt)+) => ({
+      crate::log::log(crate::log::Level::Info, module_path!(), format_args!($($args)+));
+      });
+}
+#[macro_export]
+macro_rules! debug
+{
+  ($($args:tt)+) => ({
+      crate::log::log(crate::log::Level::Debug, module_path!(), format_args!($($args)+));
+      });
+}
+#[macro_export]
+macro_rules! trace
+{
+  ($($args:tt)+) => ({
+      crate::log::log(crate::log::Level::Trace, module_path!(), format_args!($($args)+));
+      });
+}
+
 // ///////////////////////////////////
 // / LANGUAGE STRUCTURES / FUNCTIONS
 // ///////////////////////////////////
@@ -47,6 +84,7 @@ extern "C" fn eh_personality() {}
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+  console::start_panic();
   print!("Aborting: ");
   if let Some(p) = info.location() {
     println!(
@@ -59,6 +97,7 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
   else {
     println!("no information available.");
   }
+  backtrace::print_backtrace();
   abort();
 }
 #[no_mangle]
@@ -75,69 +114,194 @@ pub fn id_map_range(root: &mut page::Table,
                     end: usize,
                     bits: i64)
 {
-  let mut memaddr = start & !(page::PAGE_SIZE - 1);
-  let num_kb_pages =
-    (page::align_val(end, 12) - memaddr) / page::PAGE_SIZE;
+  let memaddr = start & !(page::PAGE_SIZE - 1);
+  let len = page::align_val(end, 12) - memaddr;
 
-  for _ in 0..num_kb_pages {
-    page::map(root, memaddr, memaddr, bits, 0);
-    memaddr += 1 << 12;
-  }
+  // map_range uses megapages and gigapages wherever it can. These are
+  // global mappings, so they're in every address space's TLB entries.
+  page::map_range(root, memaddr, memaddr, len, bits, ipi::ALL_ASIDS);
 }
 
+// ///////////////////////////////////
+// / KERNEL MEMORY MAP
+// ///////////////////////////////////
 extern "C" {
-  fn switch_to_user(frame: usize, mepc: usize, satp: usize) -> !;
+  static TEXT_START: usize;
+  static TEXT_END: usize;
+  static RODATA_START: usize;
+  static RODATA_END: usize;
+  static DATA_START: usize;
+  static DATA_END: usize;
+  static BSS_START: usize;
+  static BSS_END: usize;
+  static KERNEL_STACK_START: usize;
+  static KERNEL_STACK_END: usize;
+  static HEAP_START: usize;
+  static HEAP_SIZE: usize;
+  // The kernel's SATP. s_trap_vector switches to it on every trap.
+  static mut KERNEL_TABLE: usize;
+}
+
+// MMIO regions the kernel needs to reach
+const RTC_START: usize = 0x0010_1000;
+const RTC_END: usize = 0x0010_2000;
+const CLINT_START: usize = 0x0200_0000;
+const CLINT_END: usize = 0x0201_0000;
+const PLIC_START: usize = 0x0c00_0000;
+const PLIC_END: usize = 0x0c40_0000;
+// What a 16550 takes up
+const UART_SIZE: usize = 0x100;
+
+// Map the kernel into a page table. Every section gets only the
+// permissions it needs, so the kernel can't write its own code or run
+// its data. This goes into the kernel's table and into every process'
+// table as well, so that the trap vector can run before it switches
+// satp. Nothing here has the user bit, so processes can't see it.
+pub fn map_kernel(root: &mut page::Table) {
+  // The MMU may fault instead of setting A and D for us, so set them.
+  let kbits = page::EntryBits::Global.val()
+              | page::EntryBits::Access.val()
+              | page::EntryBits::Dirty.val();
+  let rx = page::EntryBits::ReadExecute.val() | kbits;
+  let r = page::EntryBits::Read.val() | kbits;
+  let rw = page::EntryBits::ReadWrite.val() | kbits;
+
+  unsafe {
+    id_map_range(root, TEXT_START, TEXT_END, rx);
+    id_map_range(root, RODATA_START, RODATA_END, r);
+    id_map_range(root, DATA_START, DATA_END, rw);
+    id_map_range(root, BSS_START, BSS_END, rw);
+    id_map_range(root, KERNEL_STACK_START, KERNEL_STACK_END, rw);
+    id_map_range(root, HEAP_START, HEAP_START + HEAP_SIZE, rw);
+  }
+  id_map_range(root, RTC_START, RTC_END, rw);
+  id_map_range(root, CLINT_START, CLINT_END, rw);
+  id_map_range(root, PLIC_START, PLIC_END, rw);
+  for port in uart::ports() {
+    id_map_range(root, port.base_address(), port.base_address() + UART_SIZE, rw);
+  }
 }
+
 // ///////////////////////////////////
 // / ENTRY POINT
 // ///////////////////////////////////
+// kinit runs in machine mode with the MMU off. It sets up memory and
+// the kernel's page table, and returns the SATP that boot.S turns on
+// before it drops into kmain in supervisor mode. dtb is the device tree
+// QEMU gave us.
 #[no_mangle]
-extern "C" fn kinit() {
-  uart::Uart::new(0x1000_0000).init();
+extern "C" fn kinit(dtb: usize) -> usize {
+  uart::UART0.init();
   page::init();
+  fdt::init(dtb);
+  timer::init();
   kmem::init();
-  let ret = process::init();
-  println!("Init process created at address 0x{:08x}", ret);
-  plic::set_threshold(0);
-  plic::enable(10);
-  plic::set_priority(10, 1);
-  println!("UART interrupts have been enabled and are awaiting your command.");
-  println!("Getting ready for first process.");
-  println!("Issuing the first context-switch timer.");
+  cmdline::init();
+  console::init();
+
+  let root = unsafe { &mut *kmem::get_page_table() };
+  map_kernel(root);
 
   unsafe {
-    let mtimecmp = 0x0200_4000 as *mut u64;
-    let mtime = 0x0200_bff8 as *const u64;
-    mtimecmp.write_volatile(mtime.read_volatile() + 10_000_000);
+    // Machine mode traps land on this hart's kernel trap frame and
+    // run on their own stack, away from the kernel's.
+    let frame = &mut cpu::KERNEL_TRAP_FRAME[0];
+    frame.trap_stack = page::zalloc(1).add(page::PAGE_SIZE);
+    frame.hartid = 0;
+    cpu::mscratch_write(frame as *mut cpu::TrapFrame as usize);
+    // Until the scheduler switches to something, cpu::current_hart
+    // finds the hart here.
+    cpu::sscratch_write(frame as *mut cpu::TrapFrame as usize);
+
+    KERNEL_TABLE = cpu::build_satp(page::paging_mode(), 0, root as *mut page::Table as usize);
+    KERNEL_TABLE
   }
+}
 
-  let (frame, mepc, satp) = sched::schedule();
-  unsafe { switch_to_user(frame, mepc, satp); }
+// kmain is where we end up in supervisor mode, running on the kernel's
+// page table.
+#[no_mangle]
+extern "C" fn kmain() -> ! {
+  clock::init();
+  let ret = process::init();
+  info!("Init process created at address 0x{:08x}", ret);
+  plic::init();
+  plic::init_hart(0);
+  tty::init();
+  gdbstub::init();
+  uart::register_irqs();
+  info!("UART interrupts have been enabled and are awaiting your command.");
+  info!("Getting ready for first process.");
+  info!("Issuing the first context-switch timer.");
 
-  println!("This should not print...");
+  sched::set_online(0);
+  start_harts();
+
+  // The scheduler programs the context-switch timer for us.
+  sched::reschedule(0);
 
 }
+
+// Wake up the harts boot.S parked. Each one gets a machine-mode trap
+// stack from us first, since it has nowhere to take a trap until it
+// has one. Harts that don't exist never see the SIPI and never come
+// online.
+fn start_harts() {
+  for hart in 1..cpu::MAX_HARTS {
+    unsafe {
+      let frame = &mut cpu::KERNEL_TRAP_FRAME[hart];
+      frame.trap_stack = page::zalloc(1).add(page::PAGE_SIZE);
+      frame.hartid = hart;
+      let msip = (CLINT_START as *mut u32).add(hart);
+      msip.write_volatile(1);
+    }
+  }
+}
 #[no_mangle]
-extern "C" fn kinit_hart(hartid: usize) {
+extern "C" fn kinit_hart(hartid: usize) -> usize {
+  // Like kinit, this runs in machine mode, but hart 0 has already set
+  // up memory and this hart's trap frame. All that's left is to point
+  // mscratch at it and hand back the kernel's SATP.
   unsafe {
-
     cpu::mscratch_write((&mut cpu::KERNEL_TRAP_FRAME[hartid] as *mut cpu::TrapFrame) as usize);
-
     cpu::sscratch_write(cpu::mscratch_read());
-    cpu::KERNEL_TRAP_FRAME[hartid].hartid = hartid;
+    KERNEL_TABLE
   }
 }
 
+// The secondary harts' kmain. They have nothing to set up in supervisor
+// mode, so they go straight to the scheduler.
+#[no_mangle]
+extern "C" fn kmain_hart(hartid: usize) -> ! {
+  info!("Hart {} is online.", hartid);
+  plic::init_hart(hartid);
+  sched::set_online(hartid);
+  sched::reschedule(hartid);
+}
+
 // ///////////////////////////////////
 // / RUST MODULES
 // ///////////////////////////////////
 
+pub mod backtrace;
+pub mod clock;
+pub mod cmdline;
+pub mod console;
 pub mod cpu;
+pub mod fdt;
+pub mod gdbstub;
+pub mod ipi;
+pub mod irq;
 pub mod kmem;
+pub mod log;
 pub mod page;
 pub mod plic;
 pub mod process;
 pub mod sched;
+pub mod signal;
+pub mod sync;
 pub mod syscall;
+pub mod timer;
 pub mod trap;
+pub mod tty;
 pub mod uart;
\ No newline at end of file
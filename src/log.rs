@@ -0,0 +1,266 @@
+// Adam Short
+// 08/05/2020
+
+use crate::cmdline::{parse_usize, Param};
+use crate::sync::Spinlock;
+use crate::timer::{get_mtime, ticks_to_ns};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{Arguments, Error, Write};
+
+// How bad a message is. Lower is worse, and a level lets through
+// everything at it or below.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Level> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None
+        }
+    }
+
+    // syslog priorities go from 0 (emergency) to 7 (debug). Trace
+    // doesn't have one, so it shares debug's.
+    fn syslog_priority(self) -> usize {
+        match self {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        }
+    }
+
+    fn from_syslog_priority(prio: usize) -> Level {
+        match prio {
+            0..=3 => Level::Error,
+            4 | 5 => Level::Warn,
+            6 => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+}
+
+// What goes into the ring buffer, unless a module filter says otherwise
+static mut LOG_LEVEL: Level = Level::Debug;
+// What also goes out to the consoles
+static mut CONSOLE_LEVEL: Level = Level::Info;
+static mut CONSOLE_ENABLED: bool = true;
+
+// Per-module levels, by path inside the kernel: "sched" covers
+// sched.rs and anything under it. The longest match wins.
+static FILTERS: Spinlock<Vec<(String, Level)>> = Spinlock::new(Vec::new());
+
+pub fn get_log_level() -> Level {
+    unsafe { LOG_LEVEL }
+}
+
+pub fn set_log_level(level: Level) {
+    unsafe { LOG_LEVEL = level; }
+}
+
+pub fn get_console_level() -> Level {
+    unsafe { CONSOLE_LEVEL }
+}
+
+pub fn set_console_level(level: Level) {
+    unsafe { CONSOLE_LEVEL = level; }
+}
+
+pub fn set_module_level(module: &str, level: Level) {
+    let mut filters = FILTERS.lock();
+    match filters.iter_mut().find(|(m, _)| m == module) {
+        Some(filter) => filter.1 = level,
+        None => filters.push((String::from(module), level))
+    }
+}
+
+// module_path!() starts with the crate's name, which filters leave off.
+fn strip_crate(module: &str) -> &str {
+    match module.find("::") {
+        Some(i) => &module[i + 2..],
+        None => ""
+    }
+}
+
+fn level_for(module: &str) -> Level {
+    let module = strip_crate(module);
+    let filters = FILTERS.lock();
+    filters.iter()
+           .filter(|(m, _)| module == m
+                            || (module.starts_with(m.as_str()) && module[m.len()..].starts_with("::")))
+           .max_by_key(|(m, _)| m.len())
+           .map_or(get_log_level(), |&(_, level)| level)
+}
+
+// Kernel parameters
+pub const PARAMS: [Param; 3] = [
+    Param { name: "loglevel", set: param_loglevel, help: "console level, 1-8 or a name" },
+    Param { name: "quiet", set: param_quiet, help: "takes no value" },
+    Param { name: "log", set: param_log, help: "level, or module:level,..." },
+];
+
+// Like Linux, loglevel=N prints messages with a syslog priority below N.
+fn param_loglevel(value: &str) -> bool {
+    match Level::from_name(value) {
+        Some(level) => { set_console_level(level); true },
+        None => parse_usize(value).map_or(false, set_console_priority)
+    }
+}
+
+fn param_quiet(value: &str) -> bool {
+    set_console_level(Level::Warn);
+    value.is_empty()
+}
+
+// log=debug sets what's recorded for the whole kernel, and
+// log=sched:trace,irq:debug sets it for modules.
+fn param_log(value: &str) -> bool {
+    for filter in value.split(',') {
+        let (module, level) = match filter.find(':') {
+            Some(i) => (Some(&filter[..i]), &filter[i + 1..]),
+            None => (None, filter)
+        };
+        let level = match Level::from_name(level) {
+            Some(level) => level,
+            None => return false
+        };
+        match module {
+            Some(module) => set_module_level(module, level),
+            None => set_log_level(level)
+        }
+    }
+    true
+}
+
+// ///////////////////////////////////
+// / RING BUFFER
+// ///////////////////////////////////
+
+const LOG_BUF_SIZE: usize = 16 * 1024;
+
+// Every message, as text: "<6>[    1.234567] sched: message\n". The
+// number in angle brackets is the syslog priority, like Linux's, so
+// dmesg can tell the levels apart. Once it's full, new messages
+// overwrite the oldest.
+struct LogBuf {
+    buf: [u8; LOG_BUF_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl Write for LogBuf {
+    fn write_str(&mut self, s: &str) -> Result<(), Error> {
+        for &b in s.as_bytes() {
+            self.buf[(self.head + self.len) % LOG_BUF_SIZE] = b;
+            if self.len == LOG_BUF_SIZE {
+                self.head = (self.head + 1) % LOG_BUF_SIZE;
+            }
+            else {
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+static LOG_BUF: Spinlock<LogBuf> = Spinlock::new(LogBuf { buf: [0; LOG_BUF_SIZE], head: 0, len: 0 });
+
+// What the macros call. Anything the module's level lets through goes
+// into the ring buffer, and to the consoles as well if it's at or below
+// the console level.
+pub fn log(level: Level, module: &'static str, args: Arguments) {
+    if level > level_for(module) {
+        return;
+    }
+    let ns = ticks_to_ns(get_mtime());
+    let sec = ns / 1_000_000_000;
+    let usec = ns % 1_000_000_000 / 1_000;
+    let module = strip_crate(module);
+    let _ = write!(LOG_BUF.lock(), "<{}>[{:>5}.{:06}] {}: {}\n",
+                   level.syslog_priority(), sec, usec, module, args);
+    if unsafe { CONSOLE_ENABLED } && level <= get_console_level() {
+        println!("[{:>5}.{:06}] {}: {}", sec, usec, module, args);
+    }
+}
+
+// Copy out the newest messages that fit, oldest first. If the oldest
+// one doesn't fit in full, it's left out.
+pub fn read_all(out: &mut [u8]) -> usize {
+    let log = LOG_BUF.lock();
+    let mut start = log.len - log.len.min(out.len());
+    let byte = |i: usize| log.buf[(log.head + i) % LOG_BUF_SIZE];
+    if start > 0 {
+        while start < log.len && byte(start - 1) != b'\n' {
+            start += 1;
+        }
+    }
+    let n = log.len - start;
+    for (i, b) in out[..n].iter_mut().enumerate() {
+        *b = byte(start + i);
+    }
+    n
+}
+
+pub fn clear() {
+    LOG_BUF.lock().len = 0;
+}
+
+pub fn buffer_size() -> usize {
+    LOG_BUF_SIZE
+}
+
+// ///////////////////////////////////
+// / SYSLOG
+// ///////////////////////////////////
+
+// syslog(2) actions
+pub const SYSLOG_ACTION_READ_ALL: usize = 3;
+pub const SYSLOG_ACTION_READ_CLEAR: usize = 4;
+pub const SYSLOG_ACTION_CLEAR: usize = 5;
+pub const SYSLOG_ACTION_CONSOLE_OFF: usize = 6;
+pub const SYSLOG_ACTION_CONSOLE_ON: usize = 7;
+pub const SYSLOG_ACTION_CONSOLE_LEVEL: usize = 8;
+pub const SYSLOG_ACTION_SIZE_UNREAD: usize = 9;
+pub const SYSLOG_ACTION_SIZE_BUFFER: usize = 10;
+
+pub fn set_console_enabled(enabled: bool) {
+    unsafe { CONSOLE_ENABLED = enabled; }
+}
+
+// CONSOLE_LEVEL takes a syslog priority from 1 to 8, where messages
+// below it are printed.
+pub fn set_console_priority(prio: usize) -> bool {
+    if prio < 1 || prio > 8 {
+        return false;
+    }
+    set_console_level(Level::from_syslog_priority(prio - 1));
+    true
+}
+
+// Nothing keeps track of what has been read, so it all counts as
+// unread.
+pub fn unread() -> usize {
+    LOG_BUF.lock().len
+}
@@ -1,6 +1,12 @@
 // Adam Short
 // 08/02/2020
 
+use crate::cpu::SatpMode;
+use crate::ipi::{flush_tlb, flush_tlb_range};
+#[cfg(feature = "sv48")]
+use crate::cpu::{build_satp, satp_read, satp_write};
+use crate::sync::Spinlock;
+use alloc::vec::Vec;
 use core::mem::size_of;
 use core::ptr::null_mut;
 
@@ -9,9 +15,17 @@ extern "C" {
     static HEAP_SIZE: usize;
 }
 
-static mut ALLOC_START: usize = 0;
+// Where the pages we hand out start. The lock also covers the page
+// descriptors, since any hart may allocate or free.
+static ALLOC_START: Spinlock<usize> = Spinlock::new(0);
+static mut PAGING_MODE: SatpMode = SatpMode::Sv39;
 const PAGE_ORDER: usize = 12;
 pub const PAGE_SIZE: usize = 1 << 12;
+// Sv39 has three levels of page tables and Sv48 has four. We size our
+// arrays for the larger one and ask levels() how many are in use.
+const MAX_LEVELS: usize = 4;
+// The biggest superpage we hand out is a 1 GiB gigapage (level 2).
+const MAX_SUPERPAGE_LEVEL: usize = 2;
 
 pub const fn align_val(val: usize, order: usize) -> usize {
     let o = (1usize << order) - 1;
@@ -104,11 +118,48 @@ pub fn init() {
             (*ptr.add(i)).clear();
         }
 
-        ALLOC_START = align_val(
+        *ALLOC_START.lock() = align_val(
             HEAP_START + num_pages * size_of::<Page>(),
             PAGE_ORDER,
         );
     }
+    probe_paging_mode();
+}
+
+////////////////////////////////////////////////////////////////////////////
+// Pick the paging mode. Sv39 is the default. With the sv48 feature, we
+// try to write Sv48 into satp: the mode field is WARL, so a hart that
+// doesn't support it leaves satp alone and we stay with Sv39. This has
+// to run in machine mode, where satp doesn't translate our own accesses.
+////////////////////////////////////////////////////////////////////////////
+#[cfg(feature = "sv48")]
+fn probe_paging_mode() {
+    let old = satp_read();
+    satp_write(build_satp(SatpMode::Sv48, 0, 0));
+    if satp_read() >> 60 == SatpMode::Sv48 as usize {
+        unsafe { PAGING_MODE = SatpMode::Sv48; }
+    }
+    satp_write(old);
+}
+
+#[cfg(not(feature = "sv48"))]
+fn probe_paging_mode() {}
+
+////////////////////////////////////////////////////////////////////////////
+// The paging mode every page table is built for
+////////////////////////////////////////////////////////////////////////////
+pub fn paging_mode() -> SatpMode {
+    unsafe { PAGING_MODE }
+}
+
+////////////////////////////////////////////////////////////////////////////
+// Number of page table levels for the paging mode
+////////////////////////////////////////////////////////////////////////////
+pub fn levels() -> usize {
+    match paging_mode() {
+        SatpMode::Sv48 => 4,
+        _ => 3
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////
@@ -116,6 +167,7 @@ pub fn init() {
 ////////////////////////////////////////////////////////////////////////////
 pub fn alloc(pages: usize) -> *mut u8 {
     assert!(pages > 0);
+    let alloc_start = ALLOC_START.lock();
     unsafe {
         let num_pages = HEAP_SIZE / PAGE_SIZE;
         let ptr = HEAP_START as *mut Page;
@@ -140,7 +192,7 @@ pub fn alloc(pages: usize) -> *mut u8 {
 
                 (*ptr.add(i + pages - 1)).set_flag(PageBits::Taken);
                 (*ptr.add(i + pages - 1)).set_flag(PageBits::Last);
-                return (ALLOC_START + PAGE_SIZE * i) as *mut u8;
+                return (*alloc_start + PAGE_SIZE * i) as *mut u8;
             }
 
         }
@@ -168,8 +220,9 @@ pub fn zalloc(pages: usize) -> *mut u8 {
 ////////////////////////////////////////////////////////////////////////////    
 pub fn dealloc(ptr: *mut u8) {
     assert!(!ptr.is_null());
+    let alloc_start = ALLOC_START.lock();
     unsafe {
-        let addr = HEAP_START + (ptr as usize - ALLOC_START) / PAGE_SIZE;
+        let addr = HEAP_START + (ptr as usize - *alloc_start) / PAGE_SIZE;
         assert!(addr >= HEAP_START && addr < HEAP_START + HEAP_SIZE);
         let mut p = addr as *mut Page;
         while (*p).is_taken() && !(*p).is_last() {
@@ -183,16 +236,30 @@ pub fn dealloc(ptr: *mut u8) {
     }
 }
 
+////////////////////////////////////////////////////////////////////////////
+// Count the pages nobody has allocated. They needn't be next to each
+// other, so this only says whether single-page allocations can succeed.
+////////////////////////////////////////////////////////////////////////////
+pub fn free_pages() -> usize {
+    let _alloc_start = ALLOC_START.lock();
+    unsafe {
+        let num_pages = HEAP_SIZE / PAGE_SIZE;
+        let ptr = HEAP_START as *const Page;
+        (0..num_pages).filter(|&i| (*ptr.add(i)).is_free()).count()
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////
 // Used for debugging.
 ////////////////////////////////////////////////////////////////////////////        
 pub fn print_page_allocations() {
+    let alloc_start = ALLOC_START.lock();
     unsafe {
         let num_pages = HEAP_SIZE / PAGE_SIZE;
         let mut beg = HEAP_START as *const Page;
         let end = beg.add(num_pages);
-        let alloc_beg = ALLOC_START;
-        let alloc_end = ALLOC_START + num_pages * PAGE_SIZE;
+        let alloc_beg = *alloc_start;
+        let alloc_end = *alloc_start + num_pages * PAGE_SIZE;
         println!();
         println!("PAGE ALLOCATION TABLE");
         println!("META: {:p} -> {:p}", beg, end);
@@ -202,13 +269,13 @@ pub fn print_page_allocations() {
         while beg < end {
             if (*beg).is_taken() {
                 let start = beg as usize;
-                let memaddr = ALLOC_START + (start - HEAP_START) * PAGE_SIZE;
+                let memaddr = *alloc_start + (start - HEAP_START) * PAGE_SIZE;
                 println!("0x{:x} =>", memaddr);
                 loop {
                     num += 1;
                     if (*beg).is_last() {
                         let end = beg as usize;
-                        let memaddr = ALLOC_START + (end - HEAP_START) 
+                        let memaddr = *alloc_start + (end - HEAP_START) 
                                         * PAGE_SIZE + PAGE_SIZE - 1;
                         print!(
                             "0x{:x}: {:>3} page(s)",
@@ -304,65 +371,95 @@ impl Table {
 
 
 ////////////////////////////////////////////////////////////////////////////
-// Map a virtual address to a physical address using 4096-byte page size.
+// Map a virtual address to a physical address. Level 0 maps a 4096-byte
+// page, level 1 a 2 MiB megapage and level 2 a 1 GiB gigapage.
+// Returns true if a superpage had to be split or tables under the new
+// mapping were freed. The TLB can still hold what was there before, so
+// the caller has to flush the whole address space before relying on
+// the new mapping or reusing the freed tables. This doesn't flush by
+// itself, so that a caller mapping many pages only does it once.
 ////////////////////////////////////////////////////////////////////////////
-pub fn map(root: &mut Table, vaddr: usize, paddr: usize, bits: i64, level: usize) {
+pub fn map(root: &mut Table, vaddr: usize, paddr: usize, bits: i64, level: usize) -> bool {
 
     assert!(bits & 0xe != 0);
+    assert!(level < levels());
 
-    let vpn = [
-        (vaddr >> 12) & 0x1ff,
-        (vaddr >> 21) & 0x1ff,
-        (vaddr >> 30) & 0x1ff
-    ];
-
-    let ppn = [
-        (paddr >> 12) & 0x1ff,
-        (paddr >> 21) & 0x1ff,
-        (paddr >> 30) & 0x3ff_ffff
-    ];
+    let mut v = &mut root.entries[vpn(vaddr, levels() - 1)];
+    let mut changed = false;
 
-    let mut v = &mut root.entries[vpn[2]];
-
-    for i in (level..2).rev() {
-        if v.is_invalid() {
+    for i in (level..levels() - 1).rev() {
+        if v.get_entry() == 0 {
             let page = zalloc(1);
             v.set_entry((page as i64 >> 2) | EntryBits::Valid.val());
         }
+        else if v.is_leaf() || v.is_invalid() {
+            // There's a superpage in the way. Break it up so that we
+            // can put a smaller mapping inside of it.
+            split(v, i + 1);
+            changed = true;
+        }
         let entry = ((v.get_entry() & !0x3ff) << 2) as *mut Entry;
-        v = unsafe { entry.add(vpn[i]).as_mut().unwrap() };
+        v = unsafe { entry.add(vpn(vaddr, i)).as_mut().unwrap() };
+    }
+
+    // Putting a superpage over a branch would leak the tables below it.
+    if level > 0 && v.is_valid() && v.is_branch() {
+        free_tables(v, level);
+        changed = true;
     }
 
-    let entry = (ppn[2] << 28) as i64 |
-                (ppn[1] << 19) as i64 |
-                (ppn[0] << 10) as i64 |
+    // The PPN is 44 bits in both Sv39 and Sv48 and starts at bit 10.
+    let entry = (((paddr >> 12) & 0xfff_ffff_ffff) << 10) as i64 |
                 bits |
                 EntryBits::Valid.val();
     v.set_entry(entry);
+
+    changed
 }
 
 ////////////////////////////////////////////////////////////////////////////
-// Unmap and free all memory associated with a table
+// Map [vaddr, vaddr + len) to [paddr, paddr + len), using 1 GiB and 2 MiB
+// superpages wherever both addresses are aligned for them and enough of
+// the range is left. Everything else gets 4096-byte pages. If that
+// changed anything that was already mapped, the TLB is flushed for asid
+// on every hart.
 ////////////////////////////////////////////////////////////////////////////
-pub fn unmap(root: &mut Table) {
-
-    for lv2 in 0..Table::len() {
-        let ref entry_lv2 = root.entries[lv2];
-        if entry_lv2.is_valid() && entry_lv2.is_branch() {
+pub fn map_range(root: &mut Table, vaddr: usize, paddr: usize, len: usize, bits: i64, asid: usize) {
+
+    let end = align_val(vaddr + len, PAGE_ORDER);
+    let mut v = vaddr & !(PAGE_SIZE - 1);
+    let mut p = paddr & !(PAGE_SIZE - 1);
+    let mut changed = false;
+
+    while v < end {
+        let level = (0..=MAX_SUPERPAGE_LEVEL).rev()
+                    .find(|&l| {
+                        let size = level_size(l);
+                        v % size == 0 && p % size == 0 && v + size <= end
+                    })
+                    .unwrap();
+        if map(root, v, p, bits, level) {
+            changed = true;
+        }
+        v += level_size(level);
+        p += level_size(level);
+    }
 
-            let memaddr_lv1 = (entry_lv2.get_entry() & !0x3ff) << 2;
-            let table_lv1 = unsafe { (memaddr_lv1 as *mut Table).as_mut().unwrap() };
+    if changed {
+        flush_tlb(asid);
+    }
+}
 
-            for lv1 in 0..Table::len() {
+////////////////////////////////////////////////////////////////////////////
+// Unmap and free all memory associated with a table
+////////////////////////////////////////////////////////////////////////////
+pub fn unmap(root: &mut Table) {
 
-                let ref entry_lv1 = table_lv1.entries[lv1];
-                if entry_lv1.is_valid() && entry_lv1.is_branch() {
-                    let memaddr_lv0 = (entry_lv1.get_entry() & !0x3ff) << 2;
-                    dealloc(memaddr_lv0 as *mut u8);
-                }
-            }
+    let top = levels() - 1;
 
-            dealloc(memaddr_lv1 as *mut u8);
+    for entry in root.entries.iter_mut() {
+        if entry.is_valid() && entry.is_branch() {
+            free_tables(entry, top);
         }
     }
 }
@@ -371,16 +468,37 @@ pub fn unmap(root: &mut Table) {
 // Walk the page table and convert a virtual address to a physical one
 ////////////////////////////////////////////////////////////////////////////
 pub fn virt_to_phys(root: &Table, vaddr: usize) -> Option<usize> {
+    translate(root, vaddr).map(|(paddr, _)| paddr)
+}
 
-    let vpn = [
-        (vaddr >> 12) & 0x1ff,
-        (vaddr >> 21) & 0x1ff,
-        (vaddr >> 30) & 0x1ff
-    ];
+////////////////////////////////////////////////////////////////////////////
+// Convert a virtual address the way the MMU would for user mode: the page
+// has to have the user bit and allow reading, or writing if write is set.
+// The kernel is mapped into every process, so system calls must use this
+// on pointers they get from user space.
+////////////////////////////////////////////////////////////////////////////
+pub fn user_virt_to_phys(root: &Table, vaddr: usize, write: bool) -> Option<usize> {
 
-    let mut v = &root.entries[vpn[2]];
+    let (paddr, bits) = translate(root, vaddr)?;
+    let needed = if write { EntryBits::UserReadWrite.val() } else { EntryBits::User.val() | EntryBits::Read.val() };
 
-    for i in (0..=2).rev() {
+    if bits & needed == needed {
+        Some(paddr)
+    }
+    else {
+        None
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////
+// Walk the page table and return the physical address along with the
+// bits of the leaf entry that maps it
+////////////////////////////////////////////////////////////////////////////
+fn translate(root: &Table, vaddr: usize) -> Option<(usize, i64)> {
+
+    let mut v = &root.entries[vpn(vaddr, levels() - 1)];
+
+    for i in (0..levels()).rev() {
 
         if v.is_invalid() {
             break;
@@ -389,13 +507,238 @@ pub fn virt_to_phys(root: &Table, vaddr: usize) -> Option<usize> {
             let off_mask = (1 << (12 + i * 9)) - 1;
             let vaddr_pgoff = vaddr & off_mask;
             let addr = ((v.get_entry() << 2) as usize) & !off_mask;
-            return Some(addr | vaddr_pgoff);
+            return Some((addr | vaddr_pgoff, v.get_entry() & 0x3ff));
+        }
+        else if i == 0 {
+            // A branch at level 0 is malformed.
+            break;
         }
 
         let entry = ((v.get_entry() & !0x3ff) << 2) as *const Entry;
-        v = unsafe { entry.add(vpn[i - 1]).as_ref().unwrap() };
+        v = unsafe { entry.add(vpn(vaddr, i - 1)).as_ref().unwrap() };
     }
 
     None
 
-}
\ No newline at end of file
+}
+
+////////////////////////////////////////////////////////////////////////////
+// Helpers for walking the table one level at a time
+////////////////////////////////////////////////////////////////////////////
+fn vpn(vaddr: usize, level: usize) -> usize {
+    (vaddr >> (12 + level * 9)) & 0x1ff
+}
+
+fn level_size(level: usize) -> usize {
+    1 << (12 + level * 9)
+}
+
+fn entry_addr(entry: &Entry) -> usize {
+    ((entry.get_entry() & !0x3ff) << 2) as usize
+}
+
+fn table_is_empty(table: &Table) -> bool {
+    table.entries.iter().all(|e| e.get_entry() == 0)
+}
+
+////////////////////////////////////////////////////////////////////////////
+// Replace the superpage in entry (which sits at the given level) with a
+// table of the next level down that maps the same memory the same way.
+////////////////////////////////////////////////////////////////////////////
+fn split(entry: &mut Entry, level: usize) {
+
+    assert!(level > 0);
+
+    let table = zalloc(1) as *mut Table;
+    let paddr = entry_addr(entry);
+    let flags = entry.get_entry() & 0x3ff;
+    let child_size = level_size(level - 1);
+
+    for i in 0..Table::len() {
+        let child = ((paddr + i * child_size) >> 2) as i64 | flags;
+        unsafe { (*table).entries[i].set_entry(child); }
+    }
+
+    entry.set_entry((table as i64 >> 2) | EntryBits::Valid.val());
+}
+
+////////////////////////////////////////////////////////////////////////////
+// Free the table that a branch at the given level points to, along with
+// every table underneath it. Leaf pages aren't touched.
+////////////////////////////////////////////////////////////////////////////
+fn free_tables(entry: &mut Entry, level: usize) {
+
+    let table = unsafe { &mut *(entry_addr(entry) as *mut Table) };
+
+    if level > 1 {
+        for e in table.entries.iter_mut() {
+            if e.is_valid() && e.is_branch() {
+                free_tables(e, level - 1);
+            }
+        }
+    }
+
+    dealloc(table as *mut Table as *mut u8);
+    entry.set_entry(0);
+}
+
+////////////////////////////////////////////////////////////////////////////
+// Walk toward the level 0 entry for vaddr and remember the table we went
+// through at every level; path[i] holds the level i entry. A superpage
+// that [vaddr, end) covers completely stops the walk, one that it only
+// partly covers is split on the way down.
+// Returns Ok(level) for the entry the walk stopped at, or Err(level) if
+// the entry at that level is missing.
+////////////////////////////////////////////////////////////////////////////
+fn walk(root: &mut Table,
+        vaddr: usize,
+        end: usize,
+        path: &mut [*mut Table; MAX_LEVELS]) -> Result<usize, usize>
+{
+    let mut table = root as *mut Table;
+
+    for level in (1..levels()).rev() {
+        path[level] = table;
+        let v = unsafe { &mut (*table).entries[vpn(vaddr, level)] };
+        if v.get_entry() == 0 {
+            return Err(level);
+        }
+        // Branches are always valid, so an invalid entry that isn't
+        // empty is a superpage that protect() took every bit away from.
+        if v.is_leaf() || v.is_invalid() {
+            let size = level_size(level);
+            if vaddr % size == 0 && vaddr + size <= end {
+                return Ok(level);
+            }
+            split(v, level);
+        }
+        table = entry_addr(v) as *mut Table;
+    }
+
+    path[0] = table;
+    Ok(0)
+}
+
+////////////////////////////////////////////////////////////////////////////
+// Starting with the table that holds the entry at from_level, free every
+// table that no longer has any entries and clear the branch pointing at
+// it. The root is never freed. Returns true if anything was freed.
+////////////////////////////////////////////////////////////////////////////
+fn free_empty_tables(path: &[*mut Table; MAX_LEVELS], from_level: usize, vaddr: usize) -> bool {
+
+    let mut freed = false;
+
+    for level in from_level..levels() - 1 {
+        let table = unsafe { &mut *path[level] };
+        if !table_is_empty(table) {
+            break;
+        }
+        let parent = unsafe { &mut *path[level + 1] };
+        parent.entries[vpn(vaddr, level + 1)].set_entry(0);
+        dealloc(table as *mut Table as *mut u8);
+        freed = true;
+    }
+
+    freed
+}
+
+////////////////////////////////////////////////////////////////////////////
+// Unmap everything in [vaddr, vaddr + len) and return the physical memory
+// that was mapped there as (address, length) pairs, since a superpage
+// comes back as a single run. The caller decides whether to free it.
+// Superpages that stick out of the range are split first. Tables left
+// empty are freed. The TLB is flushed for asid on every hart before we
+// return, so the memory is safe to reuse.
+////////////////////////////////////////////////////////////////////////////
+pub fn unmap_range(root: &mut Table, vaddr: usize, len: usize, asid: usize) -> Vec<(usize, usize)> {
+
+    let mut pages = Vec::new();
+    let mut path = [null_mut(); MAX_LEVELS];
+    let end = align_val(vaddr + len, PAGE_ORDER);
+    let mut addr = vaddr & !(PAGE_SIZE - 1);
+    let mut freed_tables = false;
+
+    while addr < end {
+        match walk(root, addr, end, &mut path) {
+            Ok(level) => {
+                let size = level_size(level);
+                let v = unsafe { &mut (*path[level]).entries[vpn(addr, level)] };
+
+                // An entry without the valid bit can still hold a page
+                // (see protect), so only an all-zero entry is unmapped.
+                if v.get_entry() != 0 {
+                    pages.push((entry_addr(v), size));
+                    v.set_entry(0);
+                }
+
+                // Once we're past the last entry this table covers, see
+                // if it and the tables above it can be given back.
+                let next = addr + size;
+                if next >= end || vpn(next, level) == 0 {
+                    if free_empty_tables(&path, level, addr) {
+                        freed_tables = true;
+                    }
+                }
+                addr = next;
+            },
+            Err(level) => {
+                // Nothing is mapped until the next entry at this level.
+                addr = (addr & !(level_size(level) - 1)) + level_size(level);
+            }
+        }
+    }
+
+    // sfence.vma with an address only covers leaf entries, so freed
+    // branches need a full flush.
+    if freed_tables {
+        flush_tlb(asid);
+    }
+    else if !pages.is_empty() {
+        flush_tlb_range(vaddr, len, asid);
+    }
+
+    pages
+}
+
+////////////////////////////////////////////////////////////////////////////
+// Change the permission bits of everything in [vaddr, vaddr + len),
+// splitting superpages that stick out of the range. If no R/W/X bits are
+// given, the entries are made invalid but keep their physical memory so
+// they can be turned back on later. Every hart's TLB is flushed for
+// asid. Returns false if any page in the range wasn't mapped.
+////////////////////////////////////////////////////////////////////////////
+pub fn protect(root: &mut Table, vaddr: usize, len: usize, bits: i64, asid: usize) -> bool {
+
+    let mut all_mapped = true;
+    let mut path = [null_mut(); MAX_LEVELS];
+    let end = align_val(vaddr + len, PAGE_ORDER);
+    let mut addr = vaddr & !(PAGE_SIZE - 1);
+
+    while addr < end {
+        match walk(root, addr, end, &mut path) {
+            Ok(level) => {
+                let v = unsafe { &mut (*path[level]).entries[vpn(addr, level)] };
+                if v.get_entry() == 0 {
+                    all_mapped = false;
+                }
+                else {
+                    let ppn = v.get_entry() & !0x3ff;
+                    if bits & 0xe != 0 {
+                        v.set_entry(ppn | bits | EntryBits::Valid.val());
+                    }
+                    else {
+                        v.set_entry(ppn | bits);
+                    }
+                }
+                addr += level_size(level);
+            },
+            Err(level) => {
+                all_mapped = false;
+                addr = (addr & !(level_size(level) - 1)) + level_size(level);
+            }
+        }
+    }
+
+    flush_tlb_range(vaddr, len, asid);
+    all_mapped
+}
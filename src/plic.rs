@@ -1,54 +1,213 @@
 // Adam Short
 // 08/03/2020
 
-const PLIC_PRIORITY: usize = 0x0c00_0000;
-const PLIC_PENDING: usize = 0x0c00_1000;
-const PLIC_INT_ENABLE: usize = 0x0c00_2000;
-const PLIC_THRESHOLD: usize = 0x0c20_0000;
-const PLIC_CLAIM: usize = 0x0c20_0004;
-
-pub fn next() -> Option<u32> {
-    let claim_reg = PLIC_CLAIM as *const u32;
-    let claim_no;
-    unsafe {
-        claim_no = claim_reg.read_volatile();
-    }
-    if claim_no == 0 {
-        None
+use crate::cpu::MAX_HARTS;
+use crate::fdt;
+use crate::sync::Spinlock;
+
+// Register blocks, as offsets from the PLIC's base. Priorities are one
+// word per source, pending and enable bits are 32 sources to a word,
+// and every context gets its own enable bits and threshold/claim page.
+const PLIC_PRIORITY: usize = 0x0000;
+const PLIC_PENDING: usize = 0x1000;
+const PLIC_ENABLE: usize = 0x2000;
+const PLIC_ENABLE_STRIDE: usize = 0x80;
+const PLIC_CONTEXT: usize = 0x20_0000;
+const PLIC_CONTEXT_STRIDE: usize = 0x1000;
+const PLIC_THRESHOLD: usize = 0x0;
+const PLIC_CLAIM: usize = 0x4;
+
+// Where QEMU's virt board puts the PLIC and how many sources it has, in
+// case the device tree doesn't say
+const DEFAULT_BASE: usize = 0x0c00_0000;
+const DEFAULT_NDEV: usize = 53;
+// The most sources a PLIC can have. Source 0 doesn't exist.
+pub const MAX_IRQS: usize = 1024;
+
+static mut BASE: usize = DEFAULT_BASE;
+static mut NDEV: usize = DEFAULT_NDEV;
+
+// Which hart each source goes to, and whether it's on. Holding the lock
+// also serialises changes to the enable words, since every one of them
+// is shared by 32 sources.
+#[derive(Clone, Copy)]
+struct Route {
+    hart: u8,
+    enabled: bool,
+}
+
+static ROUTES: Spinlock<[Route; MAX_IRQS]> =
+    Spinlock::new([Route { hart: 0, enabled: false }; MAX_IRQS]);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Machine,
+    Supervisor,
+}
+
+// One hart's interrupt target in one privilege mode. On virt, each hart
+// has an M-mode context followed by an S-mode one.
+#[derive(Clone, Copy)]
+pub struct Context {
+    id: usize,
+}
+
+impl Context {
+    pub fn new(hart: usize, mode: Mode) -> Self {
+        let id = match mode {
+            Mode::Machine => hart * 2,
+            Mode::Supervisor => hart * 2 + 1,
+        };
+        Context { id }
     }
-    else {
-        Some(claim_no)
+
+    // The kernel runs in supervisor mode, so this is the one it wants.
+    pub fn supervisor(hart: usize) -> Self {
+        Context::new(hart, Mode::Supervisor)
+    }
+
+    fn reg(&self, off: usize) -> *mut u32 {
+        unsafe { (BASE + PLIC_CONTEXT + self.id * PLIC_CONTEXT_STRIDE + off) as *mut u32 }
+    }
+
+    fn enable_word(&self, id: u32) -> *mut u32 {
+        unsafe {
+            (BASE + PLIC_ENABLE + self.id * PLIC_ENABLE_STRIDE + (id as usize / 32) * 4) as *mut u32
+        }
+    }
+
+    pub fn set_threshold(&self, tsh: u8) {
+        let actual_tsh = tsh & 7;
+        unsafe { self.reg(PLIC_THRESHOLD).write_volatile(actual_tsh as u32); }
+    }
+
+    pub fn claim(&self) -> Option<u32> {
+        let claim_no = unsafe { self.reg(PLIC_CLAIM).read_volatile() };
+        if claim_no == 0 {
+            None
+        }
+        else {
+            Some(claim_no)
+        }
+    }
+
+    pub fn complete(&self, id: u32) {
+        unsafe { self.reg(PLIC_CLAIM).write_volatile(id); }
+    }
+
+    pub fn is_enabled(&self, id: u32) -> bool {
+        is_valid(id) && unsafe { self.enable_word(id).read_volatile() } & (1 << (id % 32)) != 0
+    }
+
+    // These don't take the routing lock, so they're for contexts the
+    // routing doesn't manage (machine mode) or for callers that hold it.
+    pub fn enable(&self, id: u32) {
+        if is_valid(id) {
+            let word = self.enable_word(id);
+            unsafe { word.write_volatile(word.read_volatile() | 1 << (id % 32)); }
+        }
+    }
+
+    pub fn disable(&self, id: u32) {
+        if is_valid(id) {
+            let word = self.enable_word(id);
+            unsafe { word.write_volatile(word.read_volatile() & !(1 << (id % 32))); }
+        }
+    }
+}
+
+// Find the PLIC in the device tree and start with every source off.
+// Runs in kmain, before anything asks for an interrupt.
+pub fn init() {
+    let node = fdt::find_compatible("riscv,plic0").into_iter().next()
+                   .or_else(|| fdt::find_compatible("sifive,plic-1.0.0").into_iter().next());
+    if let Some(node) = node {
+        unsafe {
+            if let Some((addr, _)) = node.reg() {
+                BASE = addr;
+            }
+            if let Some(ndev) = node.prop_u32("riscv,ndev") {
+                NDEV = (ndev as usize).min(MAX_IRQS - 1);
+            }
+        }
+    }
+    for id in 1..=num_sources() as u32 {
+        set_priority(id, 0);
+        for hart in 0..MAX_HARTS {
+            Context::supervisor(hart).disable(id);
+        }
     }
 }
 
+// Let a hart take interrupts. Each hart calls this for itself.
+pub fn init_hart(hart: usize) {
+    Context::supervisor(hart).set_threshold(0);
+}
 
-pub fn complete(id: u32) {
-    let complete_reg = PLIC_CLAIM as *mut u32;
-    unsafe { complete_reg.write_volatile(id); }
+// How many sources there are. They're numbered 1 to this.
+pub fn num_sources() -> usize {
+    unsafe { NDEV }
 }
 
-pub fn set_threshold(tsh: u8) {
-    let actual_tsh = tsh & 7;
-    let tsh_reg = PLIC_THRESHOLD as *mut u32;
-    unsafe { tsh_reg.write_volatile(actual_tsh as u32); }
+fn is_valid(id: u32) -> bool {
+    id != 0 && id as usize <= num_sources()
+}
+
+pub fn set_priority(id: u32, prio: u8) {
+    if is_valid(id) {
+        let actual_prio = prio as u32 & 7;
+        let prio_reg = unsafe { (BASE + PLIC_PRIORITY) as *mut u32 };
+        unsafe { prio_reg.add(id as usize).write_volatile(actual_prio); }
+    }
 }
 
 pub fn is_pending(id: u32) -> bool {
-    let pend = PLIC_PENDING as *const u32;
-    let actual_id = 1 << id;
-    let pend_ids;
-    unsafe { pend_ids = pend.read_volatile(); }
-    actual_id & pend_ids != 0
+    if !is_valid(id) {
+        return false;
+    }
+    let pend = unsafe { (BASE + PLIC_PENDING + (id as usize / 32) * 4) as *const u32 };
+    let pend_ids = unsafe { pend.read_volatile() };
+    pend_ids & (1 << (id % 32)) != 0
 }
 
+// Turn a source on, delivered to the hart it's routed to
 pub fn enable(id: u32) {
-    let enables = PLIC_INT_ENABLE as *mut u32;
-    let actual_id = 1 << id;
-    unsafe { enables.write_volatile(enables.read_volatile() | actual_id); }
+    if is_valid(id) {
+        let mut routes = ROUTES.lock();
+        routes[id as usize].enabled = true;
+        Context::supervisor(routes[id as usize].hart as usize).enable(id);
+    }
 }
 
-pub fn set_priority(id: u32, prio: u8) {
-    let actual_prio = prio as u32 & 7;
-    let prio_reg = PLIC_PRIORITY as *mut u32;
-    unsafe { prio_reg.add(id as usize).write_volatile(actual_prio); }
-}
\ No newline at end of file
+pub fn disable(id: u32) {
+    if is_valid(id) {
+        let mut routes = ROUTES.lock();
+        routes[id as usize].enabled = false;
+        Context::supervisor(routes[id as usize].hart as usize).disable(id);
+    }
+}
+
+// Send a source to one hart from now on. If it's enabled, it moves over
+// straight away.
+pub fn set_affinity(id: u32, hart: usize) -> bool {
+    if !is_valid(id) || hart >= MAX_HARTS {
+        return false;
+    }
+    let mut routes = ROUTES.lock();
+    let route = &mut routes[id as usize];
+    if route.enabled {
+        Context::supervisor(route.hart as usize).disable(id);
+        Context::supervisor(hart).enable(id);
+    }
+    route.hart = hart as u8;
+    true
+}
+
+pub fn affinity(id: u32) -> Option<usize> {
+    if is_valid(id) {
+        Some(ROUTES.lock()[id as usize].hart as usize)
+    }
+    else {
+        None
+    }
+}
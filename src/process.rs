@@ -3,25 +3,82 @@
 // Stephen Marz
 // 27 Nov 2019
 
-use crate::{cpu::TrapFrame,
-            page::{alloc,
+use crate::{clock::{vdso_page, VDSO_ADDR},
+            cmdline::{parse_usize, Param},
+            cpu::TrapFrame,
+            ipi::flush_tlb,
+            map_kernel,
+            sched::{base_level, least_loaded_hart, SchedClass},
+            sync::Spinlock,
+            syscall::{SYS_MMAP, SYS_MPROTECT, SYS_MUNMAP, SYS_NANOSLEEP, SYS_WRITE},
+            page::{align_val,
+                   alloc,
                    dealloc,
+                   free_pages,
+                   levels,
                    map,
+                   map_range,
+                   protect,
                    unmap,
+                   unmap_range,
+                   user_virt_to_phys,
                    zalloc,
                    EntryBits,
                    Table,
                    PAGE_SIZE}};
-use alloc::collections::vec_deque::VecDeque;
+use alloc::{collections::vec_deque::VecDeque, vec::Vec};
 
 // How many pages are we going to give a process for their
-// stack?
-const STACK_PAGES: usize = 2;
+// stack? stack_pages= on the command line changes it.
+static mut STACK_PAGES: usize = 2;
 // We want to adjust the stack to be at the bottom of the memory allocation
 // regardless of where it is on the kernel heap.
 const STACK_ADDR: usize = 0x1_0000_0000;
 // All processes will have a defined starting point in virtual memory.
-const PROCESS_STARTING_ADDR: usize = 0x8000_0000;
+// The kernel's text is mapped again here for user mode, because the
+// kernel itself sits at its physical address in every page table.
+const PROCESS_STARTING_ADDR: usize = 0x2000_0000;
+// The program break (brk) starts here and grows upward.
+const HEAP_ADDR: usize = 0x2_0000_0000;
+// Anonymous mmap() regions are handed out from here up to mmap_end().
+const MMAP_ADDR: usize = 0x20_0000_0000;
+
+// Protection and flag bits for mmap/mprotect. These are the same
+// values Linux uses, so a userland malloc doesn't need translating.
+pub const PROT_NONE: usize = 0;
+pub const PROT_READ: usize = 1 << 0;
+pub const PROT_WRITE: usize = 1 << 1;
+pub const PROT_EXEC: usize = 1 << 2;
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+const PROT_ALL: usize = PROT_READ | PROT_WRITE | PROT_EXEC;
+
+/// Why mmap, munmap or mprotect failed. The system calls return these
+/// as EINVAL and ENOMEM, like Linux.
+pub enum MapError {
+	/// Bad flags or protection, or an address that isn't page aligned
+	Invalid,
+	/// No room in the address space or no memory, or for mprotect, a
+	/// page that isn't mapped
+	NoMemory,
+}
+
+/// The end of the mmap window, which is the end of the lower half of the
+/// address space: 256 GiB under Sv39 and 128 TiB under Sv48.
+fn mmap_end() -> usize {
+	1 << (12 + 9 * levels() - 1)
+}
+
+/// Round len up to whole pages, or None if that overflows.
+fn page_len(len: usize) -> Option<usize> {
+	len.checked_add(PAGE_SIZE - 1).map(|len| len & !(PAGE_SIZE - 1))
+}
+
+// The range of nice values. Lower means more CPU.
+pub const NICE_MIN: i32 = -20;
+pub const NICE_MAX: i32 = 19;
 
 // Here, we store a process list. It uses the global allocator
 // that we made before and its job is to store all processes.
@@ -31,14 +88,19 @@ const PROCESS_STARTING_ADDR: usize = 0x8000_0000;
 // Rust requires that all statics be initialized, but all
 // initializations must be at compile-time. We cannot allocate
 // a VecDeque at compile time, so we are somewhat forced to
-// do this.
-pub static mut PROCESS_LIST: Option<VecDeque<Process>> = None;
+// do this. Every hart schedules out of this list, so it lives
+// behind a spinlock.
+pub static PROCESS_LIST: Spinlock<Option<VecDeque<Process>>> = Spinlock::new(None);
 // We can search through the process list to get a new PID, but
 // it's probably easier and faster just to increase the pid:
-static mut NEXT_PID: u16 = 1;
+static NEXT_PID: Spinlock<u16> = Spinlock::new(1);
 
 extern "C" {
-	fn make_syscall(a: usize) -> usize;
+	// Takes the system call number and up to four arguments, which
+	// are already in a0 to a4 where do_syscall looks for them.
+	fn make_syscall(n: usize, a1: usize, a2: usize, a3: usize, a4: usize) -> usize;
+	static TEXT_START: usize;
+	static TEXT_END: usize;
 }
 
 /// We will eventually move this function out of here, but its
@@ -51,72 +113,191 @@ fn init_process() {
 		i += 1;
 		if i > 70_000_000 {
 			unsafe {
-				make_syscall(1);
+				make_syscall(1, 0, 0, 0, 0);
 			}
 			i = 0;
 		}
 	}
 }
 
+// The test programs below run in user mode, where only their stack,
+// their own memory and the kernel's text are mapped. So they can't use
+// anything from .rodata or .data, and every message is built out of
+// immediates: eight bytes at most, in one u64.
+
+/// Write up to eight bytes to standard output.
+fn test_write(msg: u64, len: usize) {
+	unsafe {
+		make_syscall(SYS_WRITE, 1, &msg as *const u64 as usize, len, 0);
+	}
+}
+
+fn test_sleep_secs(sec: usize) {
+	let ts = [sec, 0];
+	unsafe {
+		make_syscall(SYS_NANOSLEEP, ts.as_ptr() as usize, 0, 0, 0);
+	}
+}
+
+/// Says "tick" every second, for the timers, nanosleep and the tty.
+fn test_sleep() {
+	loop {
+		test_sleep_secs(1);
+		// "tick\n"
+		test_write(0x0a_6b_63_69_74, 5);
+	}
+}
+
+/// Maps some memory, writes to it, makes it read-only and unmaps it
+/// again, then says how it went.
+fn test_mmap() {
+	let len = 4 * PAGE_SIZE;
+	let addr = unsafe {
+		make_syscall(SYS_MMAP, 0, len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS)
+	};
+	// Errors come back as -errno.
+	let mut ok = (addr as isize) > 0;
+	if ok {
+		let words = addr as *mut usize;
+		for i in 0..len / 8 {
+			unsafe { words.add(i).write_volatile(i); }
+		}
+		for i in 0..len / 8 {
+			ok = ok && unsafe { words.add(i).read_volatile() } == i;
+		}
+		unsafe {
+			ok = ok && make_syscall(SYS_MPROTECT, addr, len, PROT_READ, 0) == 0;
+			ok = ok && make_syscall(SYS_MUNMAP, addr, len, 0, 0) == 0;
+		}
+	}
+	if ok {
+		// "mmap ok\n"
+		test_write(0x0a_6b_6f_20_70_61_6d_6d, 8);
+	}
+	else {
+		// "mmap bad"
+		test_write(0x64_61_62_20_70_61_6d_6d, 8);
+	}
+	loop {
+		test_sleep_secs(60);
+	}
+}
+
 /// Add a process given a function address and then
 /// push it onto the LinkedList. Uses Process::new_default
 /// to create a new stack, etc.
 pub fn add_process_default(pr: fn()) {
-	unsafe {
-		// This is the Rust-ism that really trips up C++ programmers.
-		// PROCESS_LIST is wrapped in an Option<> enumeration, which
-		// means that the Option owns the Deque. We can only borrow from
-		// it or move ownership to us. In this case, we choose the
-		// latter, where we move ownership to us, add a process, and
-		// then move ownership back to the PROCESS_LIST.
-		// This allows mutual exclusion as anyone else trying to grab
-		// the process list will get None rather than the Deque.
-		if let Some(mut pl) = PROCESS_LIST.take() {
-			// .take() will replace PROCESS_LIST with None and give
-			// us the only copy of the Deque.
-			let p = Process::new_default(pr);
-			pl.push_back(p);
-			// Now, we no longer need the owned Deque, so we hand it
-			// back by replacing the PROCESS_LIST's None with the
-			// Some(pl).
-			PROCESS_LIST.replace(pl);
-		}
-		// TODO: When we get to multi-hart processing, we need to keep
-		// trying to grab the process list. We can do this with an
-		// atomic instruction. but right now, we're a single-processor
-		// computer.
+	// Set the process up before we take the lock, so the other harts
+	// don't have to wait on us while we allocate its memory.
+	let mut p = Process::new_default(pr);
+	// The lock guard owns the list until it goes out of scope, and
+	// anyone else who wants it spins until then.
+	if let Some(pl) = PROCESS_LIST.lock().as_mut() {
+		p.set_hart(least_loaded_hart(pl));
+		pl.push_back(p);
+	}
+}
+
+/// Find the process that owns the given trap frame and run f on
+/// it. The trap handler only knows the frame, so this is how a
+/// system call gets at the process that made it.
+pub fn with_process_by_frame<F, R>(frame: *mut TrapFrame, f: F) -> Option<R>
+	where F: FnOnce(&mut Process) -> R
+{
+	PROCESS_LIST.lock()
+	            .as_mut()
+	            .and_then(|pl| pl.iter_mut().find(|p| p.frame == frame))
+	            .map(f)
+}
+
+/// Same as with_process_by_frame, but looks the process up by PID.
+pub fn with_process_by_pid<F, R>(pid: u16, f: F) -> Option<R>
+	where F: FnOnce(&mut Process) -> R
+{
+	PROCESS_LIST.lock()
+	            .as_mut()
+	            .and_then(|pl| pl.iter_mut().find(|p| p.pid == pid))
+	            .map(f)
+}
+
+/// There's no file system yet, so the programs a process can run
+/// are functions in the kernel, looked up by path.
+const PROGRAMS: &[(&str, fn())] = &[
+	("/sbin/init", init_process),
+	("/test/sleep", test_sleep),
+	("/test/mmap", test_mmap),
+];
+
+fn find_program(path: &str) -> Option<fn()> {
+	PROGRAMS.iter().find(|&&(p, _)| p == path).map(|&(_, f)| f)
+}
+
+// Kernel parameters
+pub const PARAMS: [Param; 3] = [
+	Param { name: "init", set: param_init, help: "path of a built-in program" },
+	Param { name: "stack_pages", set: param_stack_pages, help: "pages" },
+	Param { name: "test", set: param_test, help: "path of a built-in program" },
+];
+
+static mut INIT_PATH: &str = "/sbin/init";
+// Programs to start alongside init, picked with test=
+static TESTS: Spinlock<Vec<&'static str>> = Spinlock::new(Vec::new());
+
+fn param_init(value: &'static str) -> bool {
+	if find_program(value).is_none() {
+		return false;
 	}
+	unsafe { INIT_PATH = value; }
+	true
+}
+
+fn param_stack_pages(value: &str) -> bool {
+	match parse_usize(value).filter(|&n| n > 0 && n <= 256) {
+		Some(n) => { unsafe { STACK_PAGES = n; } true },
+		None => false
+	}
+}
+
+fn param_test(value: &'static str) -> bool {
+	if find_program(value).is_none() {
+		return false;
+	}
+	TESTS.lock().push(value);
+	true
 }
 
 /// This should only be called once, and its job is to create
 /// the init process. Right now, this process is in the kernel,
-/// but later, it should call the shell.
+/// but later, it should call the shell. Any tests picked on the
+/// command line start right after it.
 pub fn init() -> usize {
-	unsafe {
-		PROCESS_LIST = Some(VecDeque::with_capacity(15));
-		add_process_default(init_process);
-		// Ugh....Rust is giving me fits over here!
-		// I just want a memory address to the trap frame, but
-		// due to the borrow rules of Rust, I'm fighting here. So,
-		// instead, let's move the value out of PROCESS_LIST, get
-		// the address, and then move it right back in.
-		let pl = PROCESS_LIST.take().unwrap();
-		let p = pl.front().unwrap().frame;
-		let func_vaddr = pl.front().unwrap().program_counter;
-		let frame = p as *const TrapFrame as usize;
-		println!("Init's frame is at 0x{:08x}", frame);
-		// Put the process list back in the global.
-		PROCESS_LIST.replace(pl);
-		// Return the first instruction's address to execute.
-		// Since we use the MMU, all start here.
-		func_vaddr
+	*PROCESS_LIST.lock() = Some(VecDeque::with_capacity(15));
+	let init_path = unsafe { INIT_PATH };
+	info!("Starting {} as init", init_path);
+	add_process_default(find_program(init_path).unwrap_or(init_process));
+	for test in TESTS.lock().iter() {
+		info!("Starting test {}", test);
+		if let Some(program) = find_program(test) {
+			add_process_default(program);
+		}
 	}
+	let guard = PROCESS_LIST.lock();
+	let pl = guard.as_ref().unwrap();
+	let p = pl.front().unwrap().frame;
+	let func_vaddr = pl.front().unwrap().program_counter;
+	drop(guard);
+	let frame = p as *const TrapFrame as usize;
+	debug!("Init's frame is at 0x{:08x}", frame);
+	// Return the first instruction's address to execute.
+	// Since we use the MMU, all start here.
+	func_vaddr
 }
 
 // Our process must be able to sleep, wait, or run.
 // Running - means that when the scheduler finds this process, it can run it.
 // Sleeping - means that the process is waiting on a certain amount of time.
 // Waiting - means that the process is waiting on I/O
+// Stopped - a job-control signal stopped it, and only SIGCONT starts it again
 // Dead - We should never get here, but we can flag a process as Dead and clean
 //        it out of the list later.
 #[repr(u8)]
@@ -124,6 +305,7 @@ pub enum ProcessState {
 	Running,
 	Sleeping,
 	Waiting,
+	Stopped,
 	Dead,
 }
 
@@ -142,6 +324,60 @@ pub struct Process {
 	state:           ProcessState,
 	data:            ProcessData,
 	sleep_until:	 usize,
+	brk:             usize,
+	mmap_next:       usize,
+	regions:         Vec<MemoryRegion>,
+	nice:            i8,
+	level:           usize,
+	cpu_time:        usize,
+	vruntime:        usize,
+	class:           SchedClass,
+	hart:            usize,
+	pgid:            u16,
+}
+
+// The raw pointers in a process point at memory only it uses, so it's
+// fine to hand it from one hart to another under the process list lock.
+unsafe impl Send for Process {}
+
+// A run of anonymous pages that the process asked for through brk or
+// mmap. Every page inside a region was allocated on behalf of the
+// process, so unlike the stack and code mappings, these are freed when
+// they are unmapped.
+#[derive(Clone, Copy)]
+pub struct MemoryRegion {
+	start:  usize,
+	len:    usize,
+	prot:   usize,
+	shared: bool,
+}
+
+impl MemoryRegion {
+	pub fn end(&self) -> usize {
+		self.start + self.len
+	}
+	pub fn get_prot(&self) -> usize {
+		self.prot
+	}
+	pub fn is_shared(&self) -> bool {
+		self.shared
+	}
+}
+
+// Convert PROT_* bits into page table entry bits. RISC-V reserves
+// write-only pages, so PROT_WRITE always implies read.
+fn prot_to_bits(prot: usize) -> i64 {
+	let mut bits = EntryBits::User.val();
+	if prot & (PROT_READ | PROT_WRITE) != 0 {
+		bits |= EntryBits::Read.val();
+	}
+	if prot & PROT_WRITE != 0 {
+		bits |= EntryBits::Write.val();
+	}
+	if prot & PROT_EXEC != 0 {
+		bits |= EntryBits::Execute.val();
+	}
+	bits
 }
 
 impl Process {
@@ -151,6 +387,9 @@ impl Process {
 	pub fn get_program_counter(&self) -> usize {
 		self.program_counter
 	}
+	pub fn get_resume_address(&self) -> usize {
+		unsafe { (*self.frame).pc }
+	}
 	pub fn get_table_address(&self) -> usize {
 		self.root as usize
 	}
@@ -163,26 +402,365 @@ impl Process {
 	pub fn get_sleep_until(&self) -> usize {
 		self.sleep_until
 	}
+	pub fn get_brk(&self) -> usize {
+		self.brk
+	}
+	pub fn set_state(&mut self, state: ProcessState) {
+		self.state = state;
+	}
+	pub fn get_nice(&self) -> i8 {
+		self.nice
+	}
+	pub fn get_level(&self) -> usize {
+		self.level
+	}
+
+	/// Change the nice value, clamped to -20..=19 like Linux. The
+	/// scheduler never puts a process in a queue above the one its
+	/// nice value allows, so the level moves along with it.
+	pub fn set_nice(&mut self, nice: i32) {
+		let nice = core::cmp::max(NICE_MIN, core::cmp::min(NICE_MAX, nice));
+		self.nice = nice as i8;
+		self.level = base_level(self.nice);
+	}
+
+	/// Move the process to another feedback queue, but never above
+	/// the one its nice value allows.
+	pub fn set_level(&mut self, level: usize) {
+		self.level = core::cmp::max(level, base_level(self.nice));
+	}
+	/// Total mtime ticks this process has spent on a CPU.
+	pub fn get_cpu_time(&self) -> usize {
+		self.cpu_time
+	}
+	pub fn add_cpu_time(&mut self, ticks: usize) {
+		self.cpu_time += ticks;
+	}
+	/// Virtual runtime for the completely fair scheduler: CPU time
+	/// scaled by how much the nice value says we deserve.
+	pub fn get_vruntime(&self) -> usize {
+		self.vruntime
+	}
+	pub fn set_vruntime(&mut self, vruntime: usize) {
+		self.vruntime = vruntime;
+	}
+	/// Which scheduling class the process is in. Real-time and deadline
+	/// processes always run before normal ones.
+	pub fn get_class(&self) -> SchedClass {
+		self.class
+	}
+	pub fn get_class_mut(&mut self) -> &mut SchedClass {
+		&mut self.class
+	}
+	pub fn set_class(&mut self, class: SchedClass) {
+		self.class = class;
+	}
+	/// The hart whose run queue this process is on. Only that hart
+	/// runs it, until load balancing moves it somewhere else.
+	pub fn get_hart(&self) -> usize {
+		self.hart
+	}
+	pub fn set_hart(&mut self, hart: usize) {
+		self.hart = hart;
+	}
+
+	/// The process group job control signals go to. Every process
+	/// starts out in a group of its own.
+	pub fn get_pgid(&self) -> u16 {
+		self.pgid
+	}
+	pub fn set_pgid(&mut self, pgid: u16) {
+		self.pgid = pgid;
+	}
+
+	/// Put the process to sleep until mtime reaches the given value.
+	/// The scheduler wakes it up once that has passed.
+	pub fn sleep(&mut self, until: usize) {
+		self.sleep_until = until;
+		self.state = ProcessState::Sleeping;
+	}
+
+	/// Copy bytes out of this process' memory. Every page touched has
+	/// to be mapped readable for user mode, otherwise we return false.
+	pub fn copy_from_user(&self, vaddr: usize, buf: &mut [u8]) -> bool {
+		let pt = unsafe { &*self.root };
+		let mut done = 0;
+		while done < buf.len() {
+			let addr = vaddr + done;
+			let paddr = match user_virt_to_phys(pt, addr, false) {
+				Some(paddr) => paddr,
+				None => return false
+			};
+			// Don't run off the end of the page; the next one can be
+			// anywhere in physical memory.
+			let chunk = core::cmp::min(PAGE_SIZE - addr % PAGE_SIZE, buf.len() - done);
+			unsafe {
+				core::ptr::copy_nonoverlapping(paddr as *const u8,
+				                               buf[done..].as_mut_ptr(),
+				                               chunk);
+			}
+			done += chunk;
+		}
+		true
+	}
+
+	/// Copy bytes into this process' memory. Every page touched has to
+	/// be mapped writable for user mode, otherwise we return false.
+	pub fn copy_to_user(&self, vaddr: usize, buf: &[u8]) -> bool {
+		let pt = unsafe { &*self.root };
+		let mut done = 0;
+		while done < buf.len() {
+			let addr = vaddr + done;
+			let paddr = match user_virt_to_phys(pt, addr, true) {
+				Some(paddr) => paddr,
+				None => return false
+			};
+			let chunk = core::cmp::min(PAGE_SIZE - addr % PAGE_SIZE, buf.len() - done);
+			unsafe {
+				core::ptr::copy_nonoverlapping(buf[done..].as_ptr(),
+				                               paddr as *mut u8,
+				                               chunk);
+			}
+			done += chunk;
+		}
+		true
+	}
+
+	/// Map len bytes of freshly zeroed pages at vaddr. If we run out
+	/// of memory halfway, the pages mapped so far are given back.
+	fn map_anonymous(&mut self, vaddr: usize, len: usize, prot: usize) -> bool {
+		// We hold the process list the whole time, so don't start on
+		// something that can't possibly fit and would only be undone.
+		if len / PAGE_SIZE > free_pages() {
+			return false;
+		}
+		let pt = unsafe { &mut *self.root };
+		let bits = prot_to_bits(prot);
+		let mut changed = false;
+		let mut addr = vaddr;
+		while addr < vaddr + len {
+			let page = zalloc(1);
+			if page.is_null() {
+				// This flushes whatever map() changed on the way.
+				self.unmap_anonymous(vaddr, addr - vaddr);
+				return false;
+			}
+			// map() insists on at least one permission bit, so
+			// PROT_NONE pages are mapped readable and the permission
+			// is taken away below.
+			let map_bits = if prot == PROT_NONE { bits | EntryBits::Read.val() } else { bits };
+			if map(pt, addr, page as usize, map_bits, 0) {
+				changed = true;
+			}
+			addr += PAGE_SIZE;
+		}
+		// protect() flushes everything it touched, which covers
+		// anything map() changed.
+		if prot == PROT_NONE {
+			protect(pt, vaddr, len, bits, self.pid as usize);
+		}
+		else if changed {
+			flush_tlb(self.pid as usize);
+		}
+		true
+	}
+
+	/// Unmap len bytes at vaddr and free the pages behind them.
+	fn unmap_anonymous(&mut self, vaddr: usize, len: usize) {
+		let pt = unsafe { &mut *self.root };
+		// The PID doubles as the address space identifier.
+		for (paddr, size) in unmap_range(pt, vaddr, len, self.pid as usize) {
+			// Anonymous memory is allocated a page at a time.
+			for page in (paddr..paddr + size).step_by(PAGE_SIZE) {
+				dealloc(page as *mut u8);
+			}
+		}
+	}
+
+	/// Move the program break. Like the Linux system call, the
+	/// returned value is the new break on success and the old break
+	/// if the request couldn't be satisfied.
+	pub fn set_brk(&mut self, new_brk: usize) -> usize {
+		if new_brk < HEAP_ADDR || new_brk >= MMAP_ADDR {
+			return self.brk;
+		}
+		let old_top = align_val(self.brk, 12);
+		let new_top = align_val(new_brk, 12);
+		if new_top > old_top {
+			if !self.map_anonymous(old_top, new_top - old_top, PROT_READ | PROT_WRITE) {
+				return self.brk;
+			}
+		}
+		else if new_top < old_top {
+			self.unmap_anonymous(new_top, old_top - new_top);
+		}
+		self.brk = new_brk;
+		self.brk
+	}
+
+	/// Map anonymous memory. Only MAP_ANONYMOUS is supported since
+	/// we don't have files yet. MAP_SHARED and MAP_PRIVATE are
+	/// remembered in the region but behave the same until we can
+	/// fork, because nobody else can see the pages either way.
+	pub fn mmap(&mut self, addr: usize, len: usize, prot: usize, flags: usize) -> Result<usize, MapError> {
+		if len == 0 || flags & MAP_ANONYMOUS == 0 || prot & !PROT_ALL != 0 {
+			return Err(MapError::Invalid);
+		}
+		let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+			MAP_SHARED => true,
+			MAP_PRIVATE => false,
+			_ => return Err(MapError::Invalid)
+		};
+		let len = page_len(len).ok_or(MapError::NoMemory)?;
+		let start = if flags & MAP_FIXED != 0 {
+			if addr % PAGE_SIZE != 0 {
+				return Err(MapError::Invalid);
+			}
+			if addr < MMAP_ADDR || addr > mmap_end() || len > mmap_end() - addr {
+				return Err(MapError::NoMemory);
+			}
+			// MAP_FIXED replaces whatever was there before.
+			self.munmap(addr, len)?;
+			addr
+		}
+		else {
+			self.find_gap(len).ok_or(MapError::NoMemory)?
+		};
+		if !self.map_anonymous(start, len, prot) {
+			return Err(MapError::NoMemory);
+		}
+		self.regions.push(MemoryRegion { start, len, prot, shared });
+		if flags & MAP_FIXED == 0 {
+			self.mmap_next = start + len;
+		}
+		Ok(start)
+	}
+
+	/// Find len bytes in the mmap window that no region uses. The
+	/// search starts where the last mapping ended and wraps around to
+	/// the start of the window, so MAP_FIXED mappings and holes left by
+	/// munmap are stepped over.
+	fn find_gap(&self, len: usize) -> Option<usize> {
+		let end = mmap_end();
+		let fits = |start: usize| {
+			len <= end - start
+			&& !self.regions.iter().any(|r| r.start < start + len && start < r.end())
+		};
+		// A gap can only start at the beginning of the window or right
+		// after a region.
+		let mut candidates: Vec<usize> = self.regions.iter().map(|r| r.end()).collect();
+		candidates.push(MMAP_ADDR);
+		candidates.push(self.mmap_next);
+		candidates.sort();
+		let next = self.mmap_next;
+		let after = candidates.iter().filter(|&&a| a >= next);
+		let before = candidates.iter().filter(|&&a| a < next);
+		after.chain(before).cloned().find(|&a| a >= MMAP_ADDR && a <= end && fits(a))
+	}
+
+	/// Unmap every page of every region that overlaps the range,
+	/// splitting regions that only partially overlap.
+	pub fn munmap(&mut self, addr: usize, len: usize) -> Result<(), MapError> {
+		if addr % PAGE_SIZE != 0 || len == 0 {
+			return Err(MapError::Invalid);
+		}
+		let end = page_len(len).and_then(|len| addr.checked_add(len)).ok_or(MapError::Invalid)?;
+		let mut kept = Vec::with_capacity(self.regions.len() + 1);
+		let regions = core::mem::replace(&mut self.regions, Vec::new());
+		for r in regions {
+			if r.end() <= addr || r.start >= end {
+				kept.push(r);
+				continue;
+			}
+			let lo = if r.start > addr { r.start } else { addr };
+			let hi = if r.end() < end { r.end() } else { end };
+			self.unmap_anonymous(lo, hi - lo);
+			if r.start < lo {
+				kept.push(MemoryRegion { len: lo - r.start, ..r });
+			}
+			if hi < r.end() {
+				kept.push(MemoryRegion { start: hi, len: r.end() - hi, ..r });
+			}
+		}
+		self.regions = kept;
+		Ok(())
+	}
+
+	/// Change the protection of a range. The whole range has to be
+	/// covered by mmap regions or the heap; otherwise nothing changes.
+	pub fn mprotect(&mut self, addr: usize, len: usize, prot: usize) -> Result<(), MapError> {
+		if addr % PAGE_SIZE != 0 || prot & !PROT_ALL != 0 {
+			return Err(MapError::Invalid);
+		}
+		if len == 0 {
+			return Ok(());
+		}
+		let end = page_len(len).and_then(|len| addr.checked_add(len)).ok_or(MapError::NoMemory)?;
+		let heap_top = align_val(self.brk, 12);
+		// Make sure every page is ours before touching anything.
+		let mut check = addr;
+		while check < end {
+			let in_heap = check >= HEAP_ADDR && check < heap_top;
+			if !in_heap && !self.regions.iter().any(|r| check >= r.start && check < r.end()) {
+				return Err(MapError::NoMemory);
+			}
+			check += PAGE_SIZE;
+		}
+		let pt = unsafe { &mut *self.root };
+		protect(pt, addr, end - addr, prot_to_bits(prot), self.pid as usize);
+		// Split the regions so each one still has a single protection.
+		let mut updated = Vec::with_capacity(self.regions.len() + 2);
+		for r in self.regions.iter() {
+			if r.end() <= addr || r.start >= end {
+				updated.push(*r);
+				continue;
+			}
+			let lo = if r.start > addr { r.start } else { addr };
+			let hi = if r.end() < end { r.end() } else { end };
+			if r.start < lo {
+				updated.push(MemoryRegion { len: lo - r.start, ..*r });
+			}
+			updated.push(MemoryRegion { start: lo, len: hi - lo, prot, ..*r });
+			if hi < r.end() {
+				updated.push(MemoryRegion { start: hi, len: r.end() - hi, ..*r });
+			}
+		}
+		self.regions = updated;
+		Ok(())
+	}
+
 	pub fn new_default(func: fn()) -> Self {
 		let func_addr = func as usize;
-		let func_vaddr = func_addr; //- 0x6000_0000;
+		let text_start = unsafe { TEXT_START };
+		let text_end = unsafe { TEXT_END };
+		let func_vaddr = PROCESS_STARTING_ADDR + (func_addr - text_start);
+		let stack_pages = unsafe { STACK_PAGES };
 		// println!("func_addr = {:x} -> {:x}", func_addr, func_vaddr);
-		// We will convert NEXT_PID below into an atomic increment when
-		// we start getting into multi-hart processing. For now, we want
-		// a process. Get it to work, then improve it!
+		let pid = {
+			let mut next = NEXT_PID.lock();
+			*next += 1;
+			*next - 1
+		};
 		let mut ret_proc =
 			Process { frame:           zalloc(1) as *mut TrapFrame,
-			          stack:           alloc(STACK_PAGES),
+			          stack:           alloc(stack_pages),
 			          program_counter: func_vaddr,
-			          pid:             unsafe { NEXT_PID },
+			          pid:             pid,
 			          root:            zalloc(1) as *mut Table,
 			          state:           ProcessState::Running,
 					  data:            ProcessData::zero(), 
-					  sleep_until:     0
+					  sleep_until:     0,
+					  brk:             HEAP_ADDR,
+					  mmap_next:       MMAP_ADDR,
+					  regions:         Vec::new(),
+					  nice:            0,
+					  level:           base_level(0),
+					  cpu_time:        0,
+					  vruntime:        0,
+					  class:           SchedClass::Normal,
+					  hart:            0,
+					  pgid:            pid,
 					};
-		unsafe {
-			NEXT_PID += 1;
-		}
 		// Now we move the stack pointer to the bottom of the
 		// allocation. The spec shows that register x2 (2) is the stack
 		// pointer.
@@ -193,7 +771,8 @@ impl Process {
 		// bottom of the memory and far away from heap allocations.
 		let saddr = ret_proc.stack as usize;
 		unsafe {
-			(*ret_proc.frame).regs[2] = STACK_ADDR + PAGE_SIZE * STACK_PAGES;
+			(*ret_proc.frame).regs[2] = STACK_ADDR + PAGE_SIZE * stack_pages;
+			(*ret_proc.frame).pc = func_vaddr;
 		}
 		// Map the stack on the MMU
 		let pt;
@@ -203,7 +782,7 @@ impl Process {
 		// We need to map the stack onto the user process' virtual
 		// memory This gets a little hairy because we need to also map
 		// the function code too.
-		for i in 0..STACK_PAGES {
+		for i in 0..stack_pages {
 			let addr = i * PAGE_SIZE;
 			map(
 			    pt,
@@ -212,24 +791,33 @@ impl Process {
 			    EntryBits::UserReadWrite.val(),
 			    0,
 			);
-			println!("Set stack from 0x{:016x} -> 0x{:016x}", STACK_ADDR + addr, saddr + addr);
+			debug!("Set stack from 0x{:016x} -> 0x{:016x}", STACK_ADDR + addr, saddr + addr);
 		}
 		// Map the program counter on the MMU and other bits
-		for i in 0..=100 {
-			let modifier = i * 0x1000;
-			map(
-				pt,
-				func_vaddr + modifier,
-				func_addr + modifier,
-				EntryBits::UserReadWriteExecute.val(),
-				0,
-			);
-		}
-		// This is the make_syscall function
-		// The reason we need this is because we're running a process
-		// that is inside of the kernel. When we start loading from a block
-		// devices, we can load the instructions anywhere in memory. 
-		map(pt, 0x8000_0000, 0x8000_0000, EntryBits::UserReadExecute.val(), 0);
+		// The process is a function inside of the kernel, so we give it
+		// the kernel's whole text. Calls are PC-relative, which means
+		// make_syscall and anything else it calls are found at the
+		// same offset from PROCESS_STARTING_ADDR. When we start loading
+		// from a block device, we can load the instructions anywhere.
+		map_range(
+			pt,
+			PROCESS_STARTING_ADDR,
+			text_start,
+			text_end - text_start,
+			EntryBits::UserReadExecute.val(),
+			ret_proc.pid as usize,
+		);
+		// Every process can read the clock page.
+		map(
+			pt,
+			VDSO_ADDR,
+			vdso_page(),
+			EntryBits::User.val() | EntryBits::Read.val(),
+			0,
+		);
+		// The trap vector runs on this table until it switches to the
+		// kernel's, so the kernel has to be here too.
+		map_kernel(pt);
 		ret_proc
 	}
 }
@@ -240,6 +828,14 @@ impl Drop for Process {
 	fn drop(&mut self) {
 		// We allocate the stack as a page.
 		dealloc(self.stack);
+		// Give back everything handed out through brk and mmap. This
+		// has to happen before unmap below frees the tables we walk.
+		let heap_top = align_val(self.brk, 12);
+		self.unmap_anonymous(HEAP_ADDR, heap_top - HEAP_ADDR);
+		let regions = core::mem::replace(&mut self.regions, Vec::new());
+		for r in regions {
+			self.unmap_anonymous(r.start, r.len);
+		}
 		// This is unsafe, but it's at the drop stage, so we won't
 		// be using this again.
 		unsafe {
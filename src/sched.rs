@@ -1,45 +1,759 @@
 // Adam Short
 // 08/03/2020
 
-use crate::process::{ProcessState, PROCESS_LIST};
+use crate::cmdline::{parse_usize, Param};
+use crate::cpu::{build_satp, current_hart, fp_prepare, TrapFrame, MAX_HARTS};
+use crate::ipi::{send_ipi, Ipi};
+use crate::page::paging_mode;
+use crate::timer::{arm, get_mtime, ms_to_ticks, us_to_ticks};
+use crate::process::{with_process_by_pid, Process, ProcessState, PROCESS_LIST};
+use alloc::collections::vec_deque::VecDeque;
+use alloc::vec::Vec;
 
-pub fn schedule() -> (usize, usize, usize) {
+extern "C" {
+    fn switch_to_user(frame: usize, mepc: usize, satp: usize) -> !;
+    fn switch_to_kernel(frame: usize, mepc: usize, satp: usize) -> !;
+    fn idle_loop();
+    static KERNEL_TABLE: usize;
+}
+
+// How long a process gets under round robin, and how long the idle
+// context waits before we look again if no timer is due either (ms).
+// timeslice= on the command line changes it.
+static mut TIMESLICE_MS: usize = 1_000;
+
+// How often a hart looks at the other run queues to see if it should
+// take over some of their work (ms)
+const BALANCE_INTERVAL_MS: usize = 100;
+
+// The PID each hart is running (0 for idle) and when it started
+static mut CURRENT: [(u16, usize); MAX_HARTS] = [(0, 0); MAX_HARTS];
+// Which harts have come up and take part in scheduling, and when each
+// last balanced its run queue
+static mut ONLINE: [bool; MAX_HARTS] = [false; MAX_HARTS];
+static mut LAST_BALANCE: [usize; MAX_HARTS] = [0; MAX_HARTS];
+
+// Every hart falls back to its own idle context when nothing can run.
+// It waits for interrupts in supervisor mode on the kernel's table.
+static mut IDLE_FRAME: [TrapFrame; MAX_HARTS] = [TrapFrame::zero(); MAX_HARTS];
+// When each hart last went idle (0 if it never has), and how many ticks
+// it has spent idle altogether
+static mut IDLE_SINCE: [usize; MAX_HARTS] = [0; MAX_HARTS];
+static mut IDLE_TICKS: [usize; MAX_HARTS] = [0; MAX_HARTS];
+
+// ///////////////////////////////////
+// / SCHEDULING POLICIES
+// ///////////////////////////////////
+
+// A scheduling policy. schedule() does the bookkeeping that every policy
+// needs (waking sleepers, CPU time, the timer) and asks the policy which
+// process runs next and for how long. Every hart has its own run queue,
+// which is the processes whose get_hart() is that hart, and its own
+// instance of the policy.
+pub trait Scheduler {
+    fn name(&self) -> &'static str;
+    // prc just gave up a hart after running for ran ticks. If it's still
+    // Running, it was preempted; otherwise it blocked.
+    fn charge(&mut self, prc: &mut Process, ran: usize);
+    // prc was asleep and just became runnable again.
+    fn wake(&mut self, _prc: &mut Process) {}
+    // Called once per schedule() on hart before picking, with the whole list.
+    fn tick(&mut self, _pl: &mut VecDeque<Process>, _now: usize, _hart: usize) {}
+    // The index into the list of the runnable process on hart to run next.
+    fn pick(&mut self, pl: &VecDeque<Process>, hart: usize) -> Option<usize>;
+    // How many ticks prc may run before it is preempted.
+    fn timeslice(&self, pl: &VecDeque<Process>, prc: &Process) -> usize;
+    // prc is moving over to this hart's run queue from the other's.
+    fn migrate(&mut self, _prc: &mut Process, _from: &dyn Scheduler) {}
+    // How far along this queue is in virtual time, for policies that
+    // keep track of one.
+    fn clock(&self) -> usize { 0 }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Policy {
+    RoundRobin,
+    Mlfq,
+    Cfs,
+}
+
+// The default can be picked at build time with the sched-rr and
+// sched-cfs features, and changed at boot with set_policy.
+#[cfg(feature = "sched-rr")]
+static mut POLICY: Policy = Policy::RoundRobin;
+#[cfg(feature = "sched-cfs")]
+static mut POLICY: Policy = Policy::Cfs;
+#[cfg(not(any(feature = "sched-rr", feature = "sched-cfs")))]
+static mut POLICY: Policy = Policy::Mlfq;
+
+static mut ROUND_ROBIN: [RoundRobin; MAX_HARTS] = [RoundRobin; MAX_HARTS];
+static mut MLFQ: [Mlfq; MAX_HARTS] = [Mlfq { last_boost: 0 }; MAX_HARTS];
+static mut CFS: [Cfs; MAX_HARTS] = [Cfs { min_vruntime: 0 }; MAX_HARTS];
+
+pub fn get_policy() -> Policy {
+    unsafe { POLICY }
+}
+
+pub fn set_policy(policy: Policy) {
+    unsafe { POLICY = policy; }
+    info!("Scheduler policy: {}", scheduler(0).name());
+}
+
+// Kernel parameters
+pub const PARAMS: [Param; 3] = [
+    Param { name: "sched", set: param_policy, help: "rr, mlfq or cfs" },
+    Param { name: "timeslice", set: param_timeslice, help: "ms" },
+    Param { name: "rr_timeslice", set: param_rr_timeslice, help: "ms for SCHED_RR" },
+];
+
+fn param_policy(value: &str) -> bool {
+    let policy = match value {
+        "rr" => Policy::RoundRobin,
+        "mlfq" => Policy::Mlfq,
+        "cfs" => Policy::Cfs,
+        _ => return false
+    };
+    set_policy(policy);
+    true
+}
+
+// Round robin gets this much, and so does the top feedback queue, with
+// each queue below getting twice as much as the one above.
+fn param_timeslice(value: &str) -> bool {
+    match parse_usize(value).filter(|&ms| ms > 0 && ms < 1 << 20) {
+        Some(ms) => {
+            unsafe { TIMESLICE_MS = ms; }
+            for level in 0..NUM_LEVELS {
+                set_timeslice(level, ms << level);
+            }
+            true
+        },
+        None => false
+    }
+}
+
+fn param_rr_timeslice(value: &str) -> bool {
+    match parse_usize(value).filter(|&ms| ms > 0 && ms < 1 << 20) {
+        Some(ms) => { unsafe { RR_TIMESLICE_MS = ms; } true },
+        None => false
+    }
+}
+
+fn scheduler(hart: usize) -> &'static mut dyn Scheduler {
+    unsafe {
+        match POLICY {
+            Policy::RoundRobin => &mut ROUND_ROBIN[hart],
+            Policy::Mlfq => &mut MLFQ[hart],
+            Policy::Cfs => &mut CFS[hart],
+        }
+    }
+}
+
+fn is_runnable(prc: &Process, hart: usize) -> bool {
+    prc.get_hart() == hart
+    && if let ProcessState::Running = prc.get_state() { true } else { false }
+}
+
+// The policies only ever see normal processes. Real-time and deadline
+// ones are picked before we ask them.
+fn is_runnable_normal(prc: &Process, hart: usize) -> bool {
+    is_runnable(prc, hart) && prc.get_class() == SchedClass::Normal
+}
+
+// Go through the list starting just behind the front, so the process
+// that ran last comes last, and keep the first eligible one for which
+// better(candidate, best so far) holds.
+fn pick_in_order<E, F>(pl: &VecDeque<Process>, eligible: E, better: F) -> Option<usize>
+    where E: Fn(&Process) -> bool,
+          F: Fn(&Process, &Process) -> bool
+{
+    let len = pl.len();
+    let mut best: Option<usize> = None;
+    for i in 1..=len {
+        let idx = i % len;
+        if eligible(&pl[idx]) {
+            if best.map_or(true, |b| better(&pl[idx], &pl[b])) {
+                best = Some(idx);
+            }
+        }
+    }
+    best
+}
+
+// Plain round robin with a fixed timeslice
+#[derive(Clone, Copy)]
+pub struct RoundRobin;
+
+impl Scheduler for RoundRobin {
+    fn name(&self) -> &'static str {
+        "round robin"
+    }
+    fn charge(&mut self, _prc: &mut Process, _ran: usize) {}
+    fn pick(&mut self, pl: &VecDeque<Process>, hart: usize) -> Option<usize> {
+        pick_in_order(pl, |p| is_runnable_normal(p, hart), |_, _| false)
+    }
+    fn timeslice(&self, _pl: &VecDeque<Process>, _prc: &Process) -> usize {
+        ms_to_ticks(unsafe { TIMESLICE_MS })
+    }
+}
+
+// Multilevel feedback queues. Level 0 is the highest priority and gets
+// the shortest timeslice. A process that uses up its whole slice drops a
+// level; one that gives up the CPU early (sleeps or waits on I/O) goes
+// back to the top level its nice value allows. Every BOOST_INTERVAL_MS
+// everybody is moved back up so nothing starves.
+pub const NUM_LEVELS: usize = 4;
+// Per level, in ms
+static mut TIMESLICES: [usize; NUM_LEVELS] = [250, 500, 1_000, 2_000];
+const BOOST_INTERVAL_MS: usize = 10_000;
+
+#[derive(Clone, Copy)]
+pub struct Mlfq {
+    last_boost: usize,
+}
+
+// The highest feedback queue a nice value may use. Nice -20 can reach
+// level 0, nice 19 never gets above the last level.
+pub fn base_level(nice: i8) -> usize {
+    (nice as i32 + 20) as usize * NUM_LEVELS / 40
+}
+
+// In ticks, which is what the scheduler counts in
+pub fn get_timeslice(level: usize) -> usize {
+    unsafe { ms_to_ticks(TIMESLICES[level]) }
+}
+
+pub fn set_timeslice(level: usize, ms: usize) {
+    unsafe { TIMESLICES[level] = ms; }
+}
+
+impl Scheduler for Mlfq {
+    fn name(&self) -> &'static str {
+        "multilevel feedback queue"
+    }
+    fn charge(&mut self, prc: &mut Process, ran: usize) {
+        match prc.get_state() {
+            ProcessState::Running => {
+                if ran >= get_timeslice(prc.get_level()) {
+                    let lower = core::cmp::min(prc.get_level() + 1, NUM_LEVELS - 1);
+                    prc.set_level(lower);
+                }
+            },
+            _ => prc.set_level(0)
+        }
+    }
+    fn tick(&mut self, pl: &mut VecDeque<Process>, now: usize, hart: usize) {
+        if now - self.last_boost >= ms_to_ticks(BOOST_INTERVAL_MS) {
+            for prc in pl.iter_mut().filter(|p| p.get_hart() == hart) {
+                prc.set_level(0);
+            }
+            self.last_boost = now;
+        }
+    }
+    fn pick(&mut self, pl: &VecDeque<Process>, hart: usize) -> Option<usize> {
+        pick_in_order(pl, |p| is_runnable_normal(p, hart), |a, b| a.get_level() < b.get_level())
+    }
+    fn timeslice(&self, _pl: &VecDeque<Process>, prc: &Process) -> usize {
+        get_timeslice(prc.get_level())
+    }
+}
+
+// Completely fair scheduling. Every process collects virtual runtime,
+// which is the time it ran scaled by its weight, and whoever has the
+// least runs next. The weights are Linux's: each nice step is about 10%
+// of CPU, and nice 0 is 1024.
+const CFS_WEIGHTS: [usize; 40] = [
+    88761, 71755, 56483, 46273, 36291,
+    29154, 23254, 18705, 14949, 11916,
+     9548,  7620,  6100,  4904,  3906,
+     3121,  2501,  1991,  1586,  1277,
+     1024,   820,   655,   526,   423,
+      335,   272,   215,   172,   137,
+      110,    87,    70,    56,    45,
+       36,    29,    23,    18,    15,
+];
+const CFS_NICE_0_WEIGHT: usize = 1024;
+// Every runnable process should get a turn within this many us, but no
+// turn is shorter than the minimum granularity.
+const CFS_LATENCY_US: usize = 20_000;
+const CFS_MIN_GRANULARITY_US: usize = 4_000;
+
+#[derive(Clone, Copy)]
+pub struct Cfs {
+    // The smallest vruntime of anybody runnable. A process that wakes up
+    // after sleeping a long time starts here instead of with a tiny
+    // vruntime that would let it hog the CPU.
+    min_vruntime: usize,
+}
+
+fn cfs_weight(prc: &Process) -> usize {
+    CFS_WEIGHTS[(prc.get_nice() as i32 + 20) as usize]
+}
+
+impl Scheduler for Cfs {
+    fn name(&self) -> &'static str {
+        "completely fair"
+    }
+    fn charge(&mut self, prc: &mut Process, ran: usize) {
+        let delta = ran * CFS_NICE_0_WEIGHT / cfs_weight(prc);
+        prc.set_vruntime(prc.get_vruntime() + delta);
+    }
+    fn wake(&mut self, prc: &mut Process) {
+        if prc.get_vruntime() < self.min_vruntime {
+            prc.set_vruntime(self.min_vruntime);
+        }
+    }
+    fn pick(&mut self, pl: &VecDeque<Process>, hart: usize) -> Option<usize> {
+        let idx = pick_in_order(pl, |p| is_runnable_normal(p, hart),
+                                |a, b| a.get_vruntime() < b.get_vruntime());
+        if let Some(i) = idx {
+            self.min_vruntime = core::cmp::max(self.min_vruntime, pl[i].get_vruntime());
+        }
+        idx
+    }
+    fn timeslice(&self, pl: &VecDeque<Process>, prc: &Process) -> usize {
+        let total: usize = pl.iter()
+                             .filter(|p| is_runnable_normal(p, prc.get_hart()))
+                             .map(cfs_weight)
+                             .sum();
+        let slice = us_to_ticks(CFS_LATENCY_US) * cfs_weight(prc) / core::cmp::max(total, 1);
+        core::cmp::max(slice, us_to_ticks(CFS_MIN_GRANULARITY_US))
+    }
+    // Each queue's virtual time runs at its own pace, so keep the
+    // process' lead or lag relative to the queue it's joining.
+    fn migrate(&mut self, prc: &mut Process, from: &dyn Scheduler) {
+        let lag = prc.get_vruntime().saturating_sub(from.clock());
+        prc.set_vruntime(self.min_vruntime + lag);
+    }
+    fn clock(&self) -> usize {
+        self.min_vruntime
+    }
+}
+
+// ///////////////////////////////////
+// / REAL-TIME AND DEADLINE CLASSES
+// ///////////////////////////////////
+
+// Policy numbers as Linux has them for sched_setscheduler
+pub const SCHED_NORMAL: usize = 0;
+pub const SCHED_FIFO: usize = 1;
+pub const SCHED_RR: usize = 2;
+pub const SCHED_DEADLINE: usize = 6;
+pub const RT_PRIO_MIN: usize = 1;
+pub const RT_PRIO_MAX: usize = 99;
+// How long a SCHED_RR process runs before the next one of the same
+// priority gets a turn (ms, like Linux)
+static mut RR_TIMESLICE_MS: usize = 100;
+// Deadline processes together may not ask for more than this share of
+// the CPU, in millionths.
+const DEADLINE_BANDWIDTH: usize = 1_000_000;
+
+// Deadline processes run first, then real-time ones from the highest
+// priority down, then normal processes through the policy.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SchedClass {
+    Normal,
+    Fifo(u8),
+    Rr(u8),
+    Deadline(Deadline),
+}
+
+impl SchedClass {
+    pub fn policy(&self) -> usize {
+        match self {
+            SchedClass::Normal => SCHED_NORMAL,
+            SchedClass::Fifo(_) => SCHED_FIFO,
+            SchedClass::Rr(_) => SCHED_RR,
+            SchedClass::Deadline(_) => SCHED_DEADLINE,
+        }
+    }
+    pub fn priority(&self) -> usize {
+        match self {
+            SchedClass::Fifo(prio) | SchedClass::Rr(prio) => *prio as usize,
+            _ => 0
+        }
+    }
+    pub fn is_realtime(&self) -> bool {
+        *self != SchedClass::Normal
+    }
+}
+
+// Earliest deadline first with a budget. Every period, the process may
+// run for runtime ticks and should be done deadline ticks after the
+// period started. Once the budget is used up, it's throttled until the
+// next period so it can't starve everybody else. All times are in ticks.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Deadline {
+    runtime: usize,
+    deadline: usize,
+    period: usize,
+    budget: usize,
+    period_start: usize,
+}
+
+impl Deadline {
+    pub fn new(runtime: usize, deadline: usize, period: usize) -> Self {
+        let now = get_mtime();
+        Deadline { runtime, deadline, period, budget: runtime, period_start: now }
+    }
+    pub fn get_runtime(&self) -> usize {
+        self.runtime
+    }
+    pub fn get_deadline(&self) -> usize {
+        self.deadline
+    }
+    pub fn get_period(&self) -> usize {
+        self.period
+    }
+    // Share of the CPU this process reserves, in millionths. runtime
+    // comes from user space, so it's multiplied in 128 bits.
+    pub fn bandwidth(&self) -> usize {
+        (self.runtime as u128 * 1_000_000 / self.period as u128) as usize
+    }
+    fn absolute_deadline(&self) -> usize {
+        self.period_start + self.deadline
+    }
+    fn next_period(&self) -> usize {
+        self.period_start + self.period
+    }
+    // Start a new period with a full budget if the current one is over.
+    // If we missed several, skip straight to the one we're in.
+    fn replenish(&mut self, now: usize) {
+        if now >= self.next_period() {
+            let missed = (now - self.period_start) / self.period;
+            self.period_start += missed * self.period;
+            self.budget = self.runtime;
+        }
+    }
+    fn charge(&mut self, ran: usize) {
+        self.budget -= core::cmp::min(ran, self.budget);
+    }
+}
+
+fn deadline_of(prc: &Process) -> Option<Deadline> {
+    match prc.get_class() {
+        SchedClass::Deadline(dl) => Some(dl),
+        _ => None
+    }
+}
+
+// Deadline processes stay on the hart they were admitted on. Admitting
+// more than a hart has means somebody is going to miss deadlines.
+pub fn deadline_admissible(pid: u16, dl: &Deadline) -> bool {
+    let mut total = dl.bandwidth();
+    if let Some(pl) = PROCESS_LIST.lock().as_ref() {
+        let hart = pl.iter().find(|p| p.get_pid() == pid).map_or(0, |p| p.get_hart());
+        for prc in pl.iter().filter(|p| p.get_pid() != pid && p.get_hart() == hart) {
+            if let Some(dl) = deadline_of(prc) {
+                total += dl.bandwidth();
+            }
+        }
+    }
+    total <= DEADLINE_BANDWIDTH
+}
+
+// Pick a deadline or real-time process if one can run, along with how
+// long it may run. None means it's the policy's turn.
+fn pick_realtime(pl: &VecDeque<Process>, hart: usize) -> Option<(usize, usize)> {
+    let has_budget = |p: &Process| {
+        is_runnable(p, hart) && deadline_of(p).map_or(false, |dl| dl.budget > 0)
+    };
+    let earlier = |a: &Process, b: &Process| {
+        deadline_of(a).unwrap().absolute_deadline() < deadline_of(b).unwrap().absolute_deadline()
+    };
+    if let Some(idx) = pick_in_order(pl, has_budget, earlier) {
+        return Some((idx, deadline_of(&pl[idx]).unwrap().budget));
+    }
+
+    let is_rt = |p: &Process| {
+        is_runnable(p, hart) && match p.get_class() {
+            SchedClass::Fifo(_) | SchedClass::Rr(_) => true,
+            _ => false
+        }
+    };
+    let higher = |a: &Process, b: &Process| {
+        a.get_class().priority() > b.get_class().priority()
+    };
+    let mut idx = pick_in_order(pl, is_rt, higher)?;
+    // A FIFO process keeps the CPU until it blocks or somebody with a
+    // higher priority comes along, so don't let an equal priority take
+    // over from the one that ran last on this hart.
+    let prev_pid = unsafe { CURRENT[hart].0 };
+    if let Some(prev) = pl.iter().position(|p| p.get_pid() == prev_pid) {
+        if let SchedClass::Fifo(_) = pl[prev].get_class() {
+            if is_rt(&pl[prev]) && pl[prev].get_class().priority() == pl[idx].get_class().priority() {
+                idx = prev;
+            }
+        }
+    }
+    match pl[idx].get_class() {
+        SchedClass::Rr(_) => Some((idx, ms_to_ticks(unsafe { RR_TIMESLICE_MS }))),
+        _ => Some((idx, usize::max_value()))
+    }
+}
+
+// Make a process runnable again. A real-time process shouldn't wait for
+// the current timeslice to run out, so the hart whose queue it is in
+// goes through the scheduler right away and sorts out who goes next.
+// So does an idle hart, which would otherwise sleep until its timer.
+// Wakeups don't undo a stop or a kill; only resume() does the first.
+pub fn wake_up(prc: &mut Process) {
+    match prc.get_state() {
+        ProcessState::Stopped | ProcessState::Dead => {},
+        _ => resume(prc)
+    }
+}
+
+pub fn resume(prc: &mut Process) {
+    let hart = prc.get_hart();
+    prc.set_state(ProcessState::Running);
+    if !prc.get_class().is_realtime() {
+        scheduler(hart).wake(prc);
+    }
+    if prc.get_class().is_realtime() || (hart != current_hart() && is_idle(hart)) {
+        send_ipi(1 << hart, Ipi::Reschedule);
+    }
+}
+
+// The timer callback for processes that went to sleep. arg is the PID.
+pub fn wake_sleeper(pid: usize) {
+    with_process_by_pid(pid as u16, |p| {
+        if let ProcessState::Sleeping = p.get_state() {
+            wake_up(p);
+        }
+    });
+}
+
+// Have this hart go through schedule() as soon as it leaves the trap.
+pub fn preempt_soon() {
+    send_ipi(1 << current_hart(), Ipi::Reschedule);
+}
+
+// ///////////////////////////////////
+// / SCHEDULER CORE
+// ///////////////////////////////////
+
+// A hart calls this once it's ready to run processes. Until then, load
+// balancing and new processes leave it alone.
+pub fn set_online(hart: usize) {
+    unsafe { ONLINE[hart] = true; }
+}
+
+pub fn is_online(hart: usize) -> bool {
+    unsafe { ONLINE[hart] }
+}
+
+fn is_idle(hart: usize) -> bool {
+    unsafe { CURRENT[hart].0 == 0 }
+}
+
+pub fn is_current(hart: usize, pid: u16) -> bool {
+    unsafe { CURRENT[hart].0 == pid }
+}
+
+// How many runnable processes are queued on each hart
+fn queue_lengths(pl: &VecDeque<Process>) -> [usize; MAX_HARTS] {
+    let mut lens = [0; MAX_HARTS];
+    for prc in pl.iter() {
+        if is_runnable(prc, prc.get_hart()) {
+            lens[prc.get_hart()] += 1;
+        }
+    }
+    lens
+}
+
+// The online hart with the fewest runnable processes. New processes go
+// there.
+pub fn least_loaded_hart(pl: &VecDeque<Process>) -> usize {
+    let lens = queue_lengths(pl);
+    (0..MAX_HARTS).filter(|&h| is_online(h))
+                  .min_by_key(|&h| lens[h])
+                  .unwrap_or(0)
+}
+
+// Pull one process over from the busiest hart if it has at least two
+// more runnable processes than we do. Only normal processes move, since
+// deadline ones were admitted against their hart's bandwidth, and never
+// one that another hart is running right now.
+fn balance(pl: &mut VecDeque<Process>, hart: usize) {
+    let lens = queue_lengths(pl);
+    let busiest = match (0..MAX_HARTS).filter(|&h| h != hart && is_online(h))
+                                      .max_by_key(|&h| lens[h]) {
+        Some(h) => h,
+        None => return
+    };
+    if lens[busiest] < lens[hart] + 2 {
+        return;
+    }
+    let running = unsafe { CURRENT[busiest].0 };
+    if let Some(prc) = pl.iter_mut().find(|p| {
+        is_runnable_normal(p, busiest) && p.get_pid() != running
+    }) {
+        prc.set_hart(hart);
+        scheduler(hart).migrate(prc, scheduler(busiest));
+    }
+}
+
+pub fn idle_frame_address(hart: usize) -> usize {
+    unsafe { &mut IDLE_FRAME[hart] as *mut TrapFrame as usize }
+}
+
+// Called on every trap. If the hart was idle, add the time it just
+// spent waiting to its idle count. We might go straight back to idle
+// after the trap, so start counting again from now.
+pub fn account_idle(frame: *mut TrapFrame, hart: usize) {
+    unsafe {
+        if frame as usize == idle_frame_address(hart) && IDLE_SINCE[hart] != 0 {
+            let now = get_mtime();
+            IDLE_TICKS[hart] += now - IDLE_SINCE[hart];
+            IDLE_SINCE[hart] = now;
+        }
+    }
+}
+
+pub fn get_idle_ticks(hart: usize) -> usize {
+    unsafe { IDLE_TICKS[hart] }
+}
+
+// How busy a hart has been since boot, in percent. mtime starts at zero
+// when the machine comes up, so it doubles as the uptime.
+pub fn utilisation(hart: usize) -> usize {
+    let uptime = get_mtime();
+    if uptime == 0 {
+        return 0;
+    }
+    100 - get_idle_ticks(hart) * 100 / uptime
+}
+
+// Print how much CPU time every process has had, so we can check that
+// a policy is as fair as it claims.
+// The numbers are copied out first, since printing can't happen with
+// the process list held.
+pub fn print_cpu_times() {
+    let times: Vec<(u16, usize, i8, usize, usize)> = match PROCESS_LIST.lock().as_ref() {
+        Some(pl) => pl.iter()
+                      .map(|prc| (prc.get_pid(), prc.get_hart(), prc.get_nice(),
+                                  prc.get_cpu_time(), prc.get_vruntime()))
+                      .collect(),
+        None => return
+    };
+    println!("Policy: {}", scheduler(0).name());
+    println!("  PID  HART  NICE        CPU TICKS         VRUNTIME");
+    for (pid, hart, nice, cpu_time, vruntime) in times {
+        println!("{:>5} {:>5} {:>5} {:>16} {:>16}", pid, hart, nice, cpu_time, vruntime);
+    }
+}
+
+pub fn schedule(hart: usize) -> (usize, usize, usize) {
+    let now = get_mtime();
+    let mut next_wakeup = usize::max_value();
+    let policy = scheduler(hart);
+    let mut pid: usize = 0;
+    let mut next = None;
     unsafe {
-        if let Some(mut pl) = PROCESS_LIST.take() {
-            pl.rotate_left(1);
+        // The other harts can't touch the list until the guard goes
+        // away at the end of this block.
+        if let Some(pl) = PROCESS_LIST.lock().as_mut() {
             let mut frame_addr: usize = 0;
             let mut mepc: usize = 0;
             let mut satp: usize = 0;
-            let mut pid: usize = 0;
-
-            if let Some(prc) = pl.front() {
-                match prc.get_state() {
-                    ProcessState::Running => {
-                        frame_addr = prc.get_frame_address();
-                        mepc = prc.get_program_counter();
-                        satp = prc.get_table_address() >> 12;
-                        pid = prc.get_pid() as usize;
-                    },
-                    ProcessState::Sleeping => {
-                        // Do nothing
-                    },
+            let mut slice: usize = 0;
+
+            // Charge whoever just had this hart for the time it used.
+            let (prev_pid, started) = CURRENT[hart];
+            if let Some(prev) = pl.iter_mut().find(|p| p.get_pid() == prev_pid) {
+                let ran = now - started;
+                prev.add_cpu_time(ran);
+                match prev.get_class_mut() {
+                    SchedClass::Normal => policy.charge(prev, ran),
+                    SchedClass::Deadline(dl) => dl.charge(ran),
                     _ => {}
                 }
             }
 
-            println!("Scheduling {}", pid);
-            PROCESS_LIST.replace(pl);
+            // Sleepers are woken by their kernel timers, but deadline
+            // processes get a new budget every period. If one is
+            // throttled, come back when its period is up.
+            for prc in pl.iter_mut().filter(|p| p.get_hart() == hart) {
+                if let SchedClass::Deadline(dl) = prc.get_class_mut() {
+                    dl.replenish(now);
+                    if dl.budget == 0 && dl.next_period() < next_wakeup {
+                        next_wakeup = dl.next_period();
+                    }
+                }
+            }
+
+            policy.tick(pl, now, hart);
+
+            // Look for work elsewhere every so often, and right away if
+            // we'd otherwise go idle.
+            let idle = !pl.iter().any(|p| is_runnable(p, hart));
+            if idle || now - LAST_BALANCE[hart] >= ms_to_ticks(BALANCE_INTERVAL_MS) {
+                balance(pl, hart);
+                LAST_BALANCE[hart] = now;
+            }
+
+            let picked = match pick_realtime(pl, hart) {
+                Some(rt) => Some(rt),
+                None => policy.pick(pl, hart).map(|idx| (idx, policy.timeslice(pl, &pl[idx])))
+            };
+            if let Some((idx, ticks)) = picked {
+                // The chosen one goes to the front, which keeps round
+                // robin order for the policies that care about it.
+                pl.rotate_left(idx);
+                let prc = pl.front().unwrap();
+                frame_addr = prc.get_frame_address();
+                // The trap vector saves the pc of every trap in
+                // the frame, so this is where we left off.
+                mepc = prc.get_resume_address();
+                satp = prc.get_table_address() >> 12;
+                pid = prc.get_pid() as usize;
+                slice = ticks;
+            }
+
+            // Balancing on the other harts leaves whatever is in
+            // CURRENT alone, so set it before we let go of the list.
+            CURRENT[hart] = (pid as u16, now);
 
             if frame_addr != 0 { 
+                // FIFO processes have no timeslice, they only give way
+                // to sleepers that wake up with a higher priority.
+                arm(hart, core::cmp::min(now.saturating_add(slice), next_wakeup));
+                let frame = frame_addr as *mut TrapFrame;
+                (*frame).hartid = hart;
+                fp_prepare(frame, hart);
                 if satp != 0 {
-                    return (frame_addr, mepc, (8 << 60) | (pid << 44) | satp);
+                    next = Some((frame_addr, mepc, build_satp(paging_mode(), pid, satp << 12)));
                 }
                 else {
-                    return (frame_addr, mepc, 0);
+                    next = Some((frame_addr, mepc, 0));
                 }
             }
 
         }
     }
+    // Logging can end up taking a port lock, which comes before the
+    // process list (see sync.rs), so it waits until we've let go.
+    trace!("Scheduling {}", pid);
+    if let Some(next) = next {
+        return next;
+    }
+    unsafe { CURRENT[hart] = (0, now); }
+    arm(hart, core::cmp::min(now + ms_to_ticks(unsafe { TIMESLICE_MS }), next_wakeup));
     (0, 0, 0)
 }
+
+// Run whatever the scheduler picks next, or this hart's idle context if
+// nothing is runnable. The timer interrupt or a wakeup brings us back.
+pub fn reschedule(hart: usize) -> ! {
+    let (frame, mepc, satp) = schedule(hart);
+    unsafe {
+        if frame != 0 {
+            switch_to_user(frame, mepc, satp);
+        }
+        let idle = &mut IDLE_FRAME[hart];
+        idle.hartid = hart;
+        IDLE_SINCE[hart] = get_mtime();
+        switch_to_kernel(idle_frame_address(hart), idle_loop as usize, KERNEL_TABLE);
+    }
+}
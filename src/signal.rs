@@ -0,0 +1,59 @@
+// Adam Short
+// 08/04/2020
+
+use crate::ipi::{send_ipi, Ipi};
+use crate::process::{Process, ProcessState, PROCESS_LIST};
+use crate::sched::{is_current, resume};
+
+// Signal numbers, as Linux numbers them
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGTRAP: usize = 5;
+pub const SIGKILL: usize = 9;
+pub const SIGTERM: usize = 15;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const NSIG: usize = 64;
+
+// Processes can't install handlers yet, so every signal does what it
+// does by default, straight away: most of them kill the process, the
+// stop signals stop it and SIGCONT starts it again. Anything we don't
+// know about is ignored.
+pub fn send(prc: &mut Process, sig: usize) {
+    match sig {
+        SIGINT | SIGQUIT | SIGTRAP | SIGKILL | SIGTERM => prc.set_state(ProcessState::Dead),
+        SIGSTOP | SIGTSTP => {
+            if let ProcessState::Dead = prc.get_state() {
+                return;
+            }
+            prc.set_state(ProcessState::Stopped);
+        },
+        SIGCONT => {
+            if let ProcessState::Stopped = prc.get_state() {
+                // It restarts whatever system call it was in, so it
+                // doesn't matter what it was waiting for.
+                resume(prc);
+            }
+            return;
+        },
+        _ => return
+    }
+    // If it's running on a hart right now, get it off.
+    if is_current(prc.get_hart(), prc.get_pid()) {
+        send_ipi(1 << prc.get_hart(), Ipi::Reschedule);
+    }
+}
+
+// Send a signal to every process in a group. Returns false if there
+// aren't any.
+pub fn send_group(pgid: u16, sig: usize) -> bool {
+    let mut found = false;
+    if let Some(pl) = PROCESS_LIST.lock().as_mut() {
+        for prc in pl.iter_mut().filter(|p| p.get_pgid() == pgid) {
+            send(prc, sig);
+            found = true;
+        }
+    }
+    found
+}
@@ -0,0 +1,238 @@
+// Adam Short
+// 08/04/2020
+
+use crate::cpu::{sstatus_clear, sstatus_set, TrapFrame, SSTATUS_SIE};
+use crate::process::{with_process_by_frame, with_process_by_pid, ProcessState};
+use crate::sched::{reschedule, wake_up};
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+// ///////////////////////////////////
+// / SPINLOCK
+// ///////////////////////////////////
+
+// A ticket lock. Everybody who wants the lock takes the next ticket with
+// an atomic add and waits until that ticket is being served, so harts
+// get the lock in the order they asked for it. Interrupts are off while
+// we hold it, otherwise a trap on the same hart could try to take it
+// again and spin forever.
+// On RISC-V, fetch_add is a single amoadd.w. Acquire makes it .aq, so
+// nothing we do under the lock moves up above taking it, and Release
+// makes it .rl, so nothing moves down below giving it back.
+//
+// A spinlock can't be taken twice, so locks that are held at the same
+// time are always taken in this order:
+//   1. the console registry (console.rs)
+//   2. a port's state (uart.rs), a tty's state (tty.rs), a Mutex, or
+//      whatever else a process holds when it goes onto a WaitQueue
+//   3. the process list (process.rs)
+//   4. the timers and the page and kmem allocators
+// Printing and logging take 1 and then 2, so nothing may print or log
+// while it holds the process list.
+pub struct Spinlock<T> {
+    next: AtomicU32,
+    serving: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Spinlock<T> {}
+
+pub struct SpinlockGuard<'a, T> {
+    lock: &'a Spinlock<T>,
+    // Whether interrupts were on before we took the lock
+    sie: bool,
+}
+
+impl<T> Spinlock<T> {
+    pub const fn new(data: T) -> Self {
+        Spinlock {
+            next: AtomicU32::new(0),
+            serving: AtomicU32::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SpinlockGuard<'_, T> {
+        let sie = sstatus_clear(SSTATUS_SIE) & SSTATUS_SIE != 0;
+        let ticket = self.next.fetch_add(1, Ordering::Acquire);
+        while self.serving.load(Ordering::Acquire) != ticket {
+            core::sync::atomic::spin_loop_hint();
+        }
+        SpinlockGuard { lock: self, sie }
+    }
+
+    // Take the lock only if nobody has it or is waiting for it. We only
+    // take a ticket if it would be served right away.
+    pub fn try_lock(&self) -> Option<SpinlockGuard<'_, T>> {
+        let sie = sstatus_clear(SSTATUS_SIE) & SSTATUS_SIE != 0;
+        let serving = self.serving.load(Ordering::Acquire);
+        match self.next.compare_exchange(serving, serving + 1, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => Some(SpinlockGuard { lock: self, sie }),
+            Err(_) => {
+                if sie {
+                    sstatus_set(SSTATUS_SIE);
+                }
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next.load(Ordering::Relaxed) != self.serving.load(Ordering::Relaxed)
+    }
+}
+
+impl<'a, T> Deref for SpinlockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinlockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinlockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.serving.fetch_add(1, Ordering::Release);
+        if self.sie {
+            sstatus_set(SSTATUS_SIE);
+        }
+    }
+}
+
+// ///////////////////////////////////
+// / WAIT QUEUE
+// ///////////////////////////////////
+
+// Processes waiting for something to happen. The kernel doesn't keep a
+// stack around for a process that blocks, so waiting always goes
+// straight back to the scheduler. The process picks up at frame.pc once
+// it's woken up. That's the ecall itself unless the caller moved it, so
+// the system call starts over and checks again.
+pub struct WaitQueue {
+    pids: Spinlock<Vec<u16>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { pids: Spinlock::new(Vec::new()) }
+    }
+
+    // Put the process that owns frame to sleep on this queue. guard is
+    // whatever lock protects the condition we're waiting for. We only let
+    // go of it once we're on the queue, so a wakeup can't slip in between
+    // checking the condition and going to sleep. It must not be the
+    // process list, which we take after it (see the lock order above).
+    pub fn wait<T>(&self, frame: *mut TrapFrame, guard: SpinlockGuard<T>) -> ! {
+        let pid = with_process_by_frame(frame, |p| {
+            p.set_state(ProcessState::Waiting);
+            p.get_pid()
+        });
+        if let Some(pid) = pid {
+            self.pids.lock().push(pid);
+        }
+        drop(guard);
+        unsafe { reschedule((*frame).hartid); }
+    }
+
+    // Wake up whoever has been waiting longest. Returns false if nobody
+    // was.
+    pub fn wake_one(&self) -> bool {
+        let pid = {
+            let mut pids = self.pids.lock();
+            if pids.is_empty() { None } else { Some(pids.remove(0)) }
+        };
+        match pid {
+            Some(pid) => with_process_by_pid(pid, wake_up).is_some(),
+            None => false
+        }
+    }
+
+    pub fn wake_all(&self) {
+        let pids = core::mem::replace(&mut *self.pids.lock(), Vec::new());
+        for pid in pids {
+            with_process_by_pid(pid, wake_up);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pids.lock().is_empty()
+    }
+}
+
+// ///////////////////////////////////
+// / MUTEX
+// ///////////////////////////////////
+
+// A lock that processes sleep on instead of spinning. Since a process
+// that has to wait goes back to the scheduler, this can only be taken on
+// behalf of a process in a system call, and the guard must be dropped
+// before the system call returns.
+pub struct Mutex<T> {
+    locked: Spinlock<bool>,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            locked: Spinlock::new(false),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    // Take the mutex for the process that owns frame. If somebody else
+    // has it, the process waits in state Waiting and the system call
+    // starts over when the mutex is unlocked.
+    pub fn lock(&self, frame: *mut TrapFrame) -> MutexGuard<'_, T> {
+        let mut locked = self.locked.lock();
+        if !*locked {
+            *locked = true;
+            return MutexGuard { mutex: self };
+        }
+        self.waiters.wait(frame, locked);
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let mut locked = self.locked.lock();
+        if *locked {
+            return None;
+        }
+        *locked = true;
+        Some(MutexGuard { mutex: self })
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        *self.mutex.locked.lock() = false;
+        self.mutex.waiters.wake_one();
+    }
+}
@@ -1,20 +1,664 @@
 // Adam Short
 // 08/03/2020
 
+use crate::clock::{gettime, resolution_ns, set_realtime_ns, CLOCK_REALTIME};
 use crate::cpu::TrapFrame;
+use crate::log::{self, SYSLOG_ACTION_CLEAR, SYSLOG_ACTION_CONSOLE_LEVEL,
+                 SYSLOG_ACTION_CONSOLE_OFF, SYSLOG_ACTION_CONSOLE_ON, SYSLOG_ACTION_READ_ALL,
+                 SYSLOG_ACTION_READ_CLEAR, SYSLOG_ACTION_SIZE_BUFFER, SYSLOG_ACTION_SIZE_UNREAD};
+use crate::process::{with_process_by_frame, with_process_by_pid, MapError};
+use crate::sched::{deadline_admissible, preempt_soon, reschedule, wake_sleeper, Deadline,
+                   SchedClass, RT_PRIO_MAX, RT_PRIO_MIN, SCHED_DEADLINE, SCHED_FIFO,
+                   SCHED_NORMAL, SCHED_RR};
+use crate::signal::{send, send_group, NSIG};
+use crate::timer::{add_timer_at, get_mtime, ns_to_ticks, ticks_to_ns, timebase};
+use crate::tty::{Termios, TCGETS, TCSETS, TCSETSF, TCSETSW, TERMIOS_SIZE, TIOCGPGRP,
+                 TIOCSPGRP, TTY0};
+use crate::uart::RING_SIZE;
+use alloc::vec::Vec;
+
+// System call numbers. Where there is one, we use the number Linux
+// uses on RISC-V.
+const SYS_IOCTL: usize = 29;
+const SYS_READ: usize = 63;
+pub const SYS_WRITE: usize = 64;
+pub const SYS_NANOSLEEP: usize = 101;
+const SYS_CLOCK_SETTIME: usize = 112;
+const SYS_CLOCK_GETTIME: usize = 113;
+const SYS_CLOCK_GETRES: usize = 114;
+const SYS_SYSLOG: usize = 116;
+const SYS_SCHED_SETPARAM: usize = 118;
+const SYS_SCHED_SETSCHEDULER: usize = 119;
+const SYS_SCHED_GETSCHEDULER: usize = 120;
+const SYS_SCHED_GETPARAM: usize = 121;
+const SYS_SCHED_GET_PRIORITY_MAX: usize = 125;
+const SYS_SCHED_GET_PRIORITY_MIN: usize = 126;
+const SYS_KILL: usize = 129;
+const SYS_SETPRIORITY: usize = 140;
+const SYS_GETPRIORITY: usize = 141;
+const SYS_SETPGID: usize = 154;
+const SYS_GETPGID: usize = 155;
+const SYS_GETTIMEOFDAY: usize = 169;
+const SYS_SETTIMEOFDAY: usize = 170;
+const SYS_BRK: usize = 214;
+pub const SYS_MUNMAP: usize = 215;
+pub const SYS_MMAP: usize = 222;
+pub const SYS_MPROTECT: usize = 226;
+const SYS_SCHED_SETATTR: usize = 274;
+const SYS_SCHED_GETATTR: usize = 275;
+
+// setpriority/getpriority only know about single processes for now.
+const PRIO_PROCESS: usize = 0;
+
+// struct sched_attr, which sched_setattr/sched_getattr use to carry the
+// deadline parameters. Times are in nanoseconds.
+//   u32 size, u32 policy, u64 flags, i32 nice, u32 priority,
+//   u64 runtime, u64 deadline, u64 period
+const SCHED_ATTR_SIZE: usize = 48;
+
+// Error numbers are returned negated in a0, just like Linux.
+const EBADF: usize = 9;
+const ENOMEM: usize = 12;
+const EPERM: usize = 1;
+const ESRCH: usize = 3;
+const EFAULT: usize = 14;
+const ENOTTY: usize = 25;
+const EBUSY: usize = 16;
+const EINVAL: usize = 22;
+
+fn errno(e: usize) -> usize {
+    (-(e as isize)) as usize
+}
 
 pub fn do_syscall(mepc: usize, frame: *mut TrapFrame) -> usize {
     let syscall_number;
-    unsafe { syscall_number = (*frame).regs[10]; }
+    let args;
+    unsafe {
+        syscall_number = (*frame).regs[10];
+        args = [
+            (*frame).regs[11],
+            (*frame).regs[12],
+            (*frame).regs[13],
+            (*frame).regs[14],
+        ];
+    }
     match syscall_number {
         0 => { mepc + 4 },
         1 => {
             println!("Test syscall");
             mepc + 4
         }
+        SYS_READ => {
+            // read(fd, buf, count). Standard input is the console tty.
+            // This waits for a line, or in raw mode for at least a byte.
+            if args[0] != 0 {
+                set_return(frame, errno(EBADF));
+                return mepc + 4;
+            }
+            let mut buf = [0u8; 256];
+            let len = args[2].min(buf.len());
+            let n = TTY0.read(frame, &mut buf[..len]);
+            let copied = with_process_by_frame(frame, |p| p.copy_to_user(args[1], &buf[..n]));
+            set_return(frame, if copied == Some(true) { n } else { errno(EFAULT) });
+            mepc + 4
+        },
+        SYS_WRITE => {
+            // write(fd, buf, count). Standard output and error are the
+            // console tty. Each write goes out in one piece, so output
+            // from different processes doesn't get mixed up. Long ones
+            // are cut short, and the return value says how much went.
+            if args[0] != 1 && args[0] != 2 {
+                set_return(frame, errno(EBADF));
+                return mepc + 4;
+            }
+            // The tty can double the size of a write turning \n into
+            // \r\n.
+            let mut buf = [0u8; RING_SIZE / 2];
+            let len = args[2].min(buf.len());
+            if with_process_by_frame(frame, |p| p.copy_from_user(args[1], &mut buf[..len])) != Some(true) {
+                set_return(frame, errno(EFAULT));
+                return mepc + 4;
+            }
+            TTY0.write(frame, &buf[..len]);
+            set_return(frame, len);
+            mepc + 4
+        },
+        SYS_IOCTL => {
+            // ioctl(fd, request, arg). Only the console tty has any.
+            let ret = match args[1] {
+                _ if args[0] > 2 => errno(EBADF),
+                TCGETS => {
+                    let termios = TTY0.get_termios().to_bytes();
+                    match with_process_by_frame(frame, |p| p.copy_to_user(args[2], &termios)) {
+                        Some(true) => 0,
+                        _ => errno(EFAULT)
+                    }
+                },
+                TCSETS | TCSETSW | TCSETSF => {
+                    // Output goes out as soon as it's written, so there's
+                    // never anything for TCSETSW to wait for.
+                    let mut buf = [0u8; TERMIOS_SIZE];
+                    match with_process_by_frame(frame, |p| p.copy_from_user(args[2], &mut buf)) {
+                        Some(true) => {
+                            TTY0.set_termios(Termios::from_bytes(&buf), args[1] == TCSETSF);
+                            0
+                        },
+                        _ => errno(EFAULT)
+                    }
+                },
+                TIOCGPGRP => {
+                    let pgid = TTY0.get_foreground() as i32;
+                    match with_process_by_frame(frame, |p| p.copy_to_user(args[2], &pgid.to_le_bytes())) {
+                        Some(true) => 0,
+                        _ => errno(EFAULT)
+                    }
+                },
+                TIOCSPGRP => {
+                    let mut buf = [0u8; 4];
+                    match with_process_by_frame(frame, |p| p.copy_from_user(args[2], &mut buf)) {
+                        Some(true) => {
+                            let pgid = i32::from_le_bytes(buf);
+                            if pgid <= 0 || pgid > u16::max_value() as i32 {
+                                errno(EINVAL)
+                            }
+                            else {
+                                TTY0.set_foreground(pgid as u16);
+                                0
+                            }
+                        },
+                        _ => errno(EFAULT)
+                    }
+                },
+                _ => errno(ENOTTY)
+            };
+            set_return(frame, ret);
+            mepc + 4
+        },
+        SYS_KILL => {
+            // kill(pid, sig). A positive pid is one process, 0 is the
+            // caller's group and -pgid is a whole group. Signal 0 only
+            // checks that there is somebody to send to.
+            let pid = args[0] as isize;
+            let sig = args[1];
+            let ret = if sig >= NSIG || pid == -1 {
+                errno(EINVAL)
+            }
+            else if pid > u16::max_value() as isize || pid < -(u16::max_value() as isize) {
+                errno(ESRCH)
+            }
+            else {
+                let found = if pid > 0 {
+                    with_process_by_pid(pid as u16, |p| if sig != 0 { send(p, sig) }).is_some()
+                }
+                else {
+                    let pgid = if pid == 0 {
+                        with_process_by_frame(frame, |p| p.get_pgid()).unwrap_or(0)
+                    }
+                    else {
+                        (-pid) as u16
+                    };
+                    if sig != 0 {
+                        send_group(pgid, sig)
+                    }
+                    else {
+                        group_exists(pgid)
+                    }
+                };
+                if found { 0 } else { errno(ESRCH) }
+            };
+            set_return(frame, ret);
+            mepc + 4
+        },
+        SYS_SETPGID => {
+            // setpgid(pid, pgid). Either being 0 means the caller's pid.
+            let ret = if args[1] > u16::max_value() as usize {
+                errno(EINVAL)
+            }
+            else {
+                let moved = with_target(frame, args[0], |p| {
+                    let pgid = if args[1] == 0 { p.get_pid() } else { args[1] as u16 };
+                    p.set_pgid(pgid);
+                });
+                if moved.is_some() { 0 } else { errno(ESRCH) }
+            };
+            set_return(frame, ret);
+            mepc + 4
+        },
+        SYS_GETPGID => {
+            // getpgid(pid)
+            let ret = match with_target(frame, args[0], |p| p.get_pgid()) {
+                Some(pgid) => pgid as usize,
+                None => errno(ESRCH)
+            };
+            set_return(frame, ret);
+            mepc + 4
+        },
+        SYS_NANOSLEEP => {
+            // nanosleep(req, rem). We always sleep the whole time, so
+            // rem is never written.
+            let mut ts = [0u8; 16];
+            let read = with_process_by_frame(frame, |p| p.copy_from_user(args[0], &mut ts));
+            if read != Some(true) {
+                set_return(frame, errno(EFAULT));
+                return mepc + 4;
+            }
+            let mut sec = [0u8; 8];
+            let mut nsec = [0u8; 8];
+            sec.copy_from_slice(&ts[0..8]);
+            nsec.copy_from_slice(&ts[8..16]);
+            let sec = i64::from_le_bytes(sec);
+            let nsec = i64::from_le_bytes(nsec);
+            if sec < 0 || nsec < 0 || nsec >= 1_000_000_000 {
+                set_return(frame, errno(EINVAL));
+                return mepc + 4;
+            }
+            // Anything too long to count up to is forever, and nothing
+            // wakes the process up.
+            let until = (sec as usize).checked_mul(timebase())
+                                      .and_then(|t| t.checked_add(ns_to_ticks(nsec as usize)))
+                                      .and_then(|t| t.checked_add(get_mtime()))
+                                      .unwrap_or(usize::max_value());
+            if let Some(pid) = with_process_by_frame(frame, |p| { p.sleep(until); p.get_pid() }) {
+                if until != usize::max_value() {
+                    add_timer_at(until, 0, wake_sleeper, pid as usize);
+                }
+            }
+            // We won't come back through the trap vector, so record
+            // where to pick up once we wake up.
+            set_return(frame, 0);
+            unsafe {
+                (*frame).pc = mepc + 4;
+                reschedule((*frame).hartid);
+            }
+        },
+        SYS_CLOCK_GETTIME | SYS_CLOCK_GETRES => {
+            // clock_gettime(clock, ts) and clock_getres(clock, res).
+            // getres allows a null res.
+            let ret = match gettime(args[0]) {
+                Some(_) if syscall_number == SYS_CLOCK_GETRES && args[1] == 0 => 0,
+                Some(ns) => {
+                    let ns = if syscall_number == SYS_CLOCK_GETRES { resolution_ns() } else { ns };
+                    write_pair(frame, args[1], ns / 1_000_000_000, ns % 1_000_000_000)
+                },
+                None => errno(EINVAL)
+            };
+            set_return(frame, ret);
+            mepc + 4
+        },
+        SYS_CLOCK_SETTIME => {
+            // clock_settime(clock, ts). Only the real-time clock can be
+            // set; the monotonic clock is the time counter.
+            let ret = match read_pair(frame, args[1]) {
+                _ if args[0] != CLOCK_REALTIME && gettime(args[0]).is_some() => errno(EPERM),
+                _ if args[0] != CLOCK_REALTIME => errno(EINVAL),
+                Some((sec, nsec)) if nsec >= 0 && nsec < 1_000_000_000 => {
+                    match to_ns(sec, nsec as u64) {
+                        Some(ns) => { set_realtime_ns(ns); 0 },
+                        None => errno(EINVAL)
+                    }
+                },
+                Some(_) => errno(EINVAL),
+                None => errno(EFAULT)
+            };
+            set_return(frame, ret);
+            mepc + 4
+        },
+        SYS_GETTIMEOFDAY => {
+            // gettimeofday(tv, tz). There's no time zone, so tz is
+            // ignored.
+            let ret = match args[0] {
+                0 => 0,
+                tv => {
+                    let ns = gettime(CLOCK_REALTIME).unwrap();
+                    write_pair(frame, tv, ns / 1_000_000_000, ns % 1_000_000_000 / 1_000)
+                }
+            };
+            set_return(frame, ret);
+            mepc + 4
+        },
+        SYS_SETTIMEOFDAY => {
+            // settimeofday(tv, tz)
+            let ret = match args[0] {
+                0 => 0,
+                tv => match read_pair(frame, tv) {
+                    Some((sec, usec)) if usec >= 0 && usec < 1_000_000 => {
+                        match to_ns(sec, usec as u64 * 1_000) {
+                            Some(ns) => { set_realtime_ns(ns); 0 },
+                            None => errno(EINVAL)
+                        }
+                    },
+                    Some(_) => errno(EINVAL),
+                    None => errno(EFAULT)
+                }
+            };
+            set_return(frame, ret);
+            mepc + 4
+        },
+        SYS_SYSLOG => {
+            // syslog(type, buf, len), which is what dmesg uses to get at
+            // the kernel log.
+            let ret = match args[0] {
+                SYSLOG_ACTION_READ_ALL | SYSLOG_ACTION_READ_CLEAR => {
+                    let mut buf = Vec::new();
+                    buf.resize(args[2].min(log::buffer_size()), 0u8);
+                    let n = log::read_all(&mut buf);
+                    match with_process_by_frame(frame, |p| p.copy_to_user(args[1], &buf[..n])) {
+                        Some(true) => {
+                            if args[0] == SYSLOG_ACTION_READ_CLEAR {
+                                log::clear();
+                            }
+                            n
+                        },
+                        _ => errno(EFAULT)
+                    }
+                },
+                SYSLOG_ACTION_CLEAR => { log::clear(); 0 },
+                SYSLOG_ACTION_CONSOLE_OFF => { log::set_console_enabled(false); 0 },
+                SYSLOG_ACTION_CONSOLE_ON => { log::set_console_enabled(true); 0 },
+                SYSLOG_ACTION_CONSOLE_LEVEL => {
+                    if log::set_console_priority(args[2]) { 0 } else { errno(EINVAL) }
+                },
+                SYSLOG_ACTION_SIZE_UNREAD => log::unread(),
+                SYSLOG_ACTION_SIZE_BUFFER => log::buffer_size(),
+                _ => errno(EINVAL)
+            };
+            set_return(frame, ret);
+            mepc + 4
+        },
+        SYS_SETPRIORITY => {
+            // setpriority(which, who, nice). who == 0 means the caller.
+            if args[0] != PRIO_PROCESS {
+                set_return(frame, errno(EINVAL));
+                return mepc + 4;
+            }
+            let nice = args[2] as i32;
+            let ret = with_target(frame, args[1], |p| p.set_nice(nice));
+            set_return(frame, if ret.is_some() { 0 } else { errno(ESRCH) });
+            mepc + 4
+        },
+        SYS_GETPRIORITY => {
+            // getpriority(which, who). Like Linux, this returns
+            // 20 - nice so that the result is never negative.
+            if args[0] != PRIO_PROCESS {
+                set_return(frame, errno(EINVAL));
+                return mepc + 4;
+            }
+            let ret = with_target(frame, args[1], |p| p.get_nice());
+            set_return(frame, match ret {
+                Some(nice) => (20 - nice as isize) as usize,
+                None => errno(ESRCH)
+            });
+            mepc + 4
+        },
+        SYS_SCHED_SETSCHEDULER | SYS_SCHED_SETPARAM => {
+            // sched_setscheduler(pid, policy, param) and
+            // sched_setparam(pid, param). param points to an int priority,
+            // which has to be 0 for normal processes and 1..=99 for
+            // real-time ones. Deadline processes go through sched_setattr.
+            let (policy, param) = if syscall_number == SYS_SCHED_SETSCHEDULER {
+                (Some(args[1]), args[2])
+            }
+            else {
+                (None, args[1])
+            };
+            let mut buf = [0u8; 4];
+            if with_process_by_frame(frame, |p| p.copy_from_user(param, &mut buf)) != Some(true) {
+                set_return(frame, errno(EFAULT));
+                return mepc + 4;
+            }
+            let prio = i32::from_le_bytes(buf);
+            let ret = with_target(frame, args[0], |p| {
+                let policy = policy.unwrap_or(p.get_class().policy());
+                match class_from(policy, prio) {
+                    Some(class) => { p.set_class(class); 0 },
+                    None => errno(EINVAL)
+                }
+            });
+            set_return(frame, ret.unwrap_or(errno(ESRCH)));
+            preempt_soon();
+            mepc + 4
+        },
+        SYS_SCHED_GETSCHEDULER => {
+            // sched_getscheduler(pid)
+            let ret = with_target(frame, args[0], |p| p.get_class().policy());
+            set_return(frame, ret.unwrap_or(errno(ESRCH)));
+            mepc + 4
+        },
+        SYS_SCHED_GETPARAM => {
+            // sched_getparam(pid, param)
+            let ret = with_target(frame, args[0], |p| p.get_class().priority() as i32);
+            let ret = match ret {
+                Some(prio) => {
+                    let written = with_process_by_frame(frame, |p| {
+                        p.copy_to_user(args[1], &prio.to_le_bytes())
+                    });
+                    if written == Some(true) { 0 } else { errno(EFAULT) }
+                },
+                None => errno(ESRCH)
+            };
+            set_return(frame, ret);
+            mepc + 4
+        },
+        SYS_SCHED_GET_PRIORITY_MAX | SYS_SCHED_GET_PRIORITY_MIN => {
+            // sched_get_priority_max(policy) and sched_get_priority_min(policy)
+            let ret = match args[0] {
+                SCHED_FIFO | SCHED_RR if syscall_number == SYS_SCHED_GET_PRIORITY_MAX => RT_PRIO_MAX,
+                SCHED_FIFO | SCHED_RR => RT_PRIO_MIN,
+                SCHED_NORMAL | SCHED_DEADLINE => 0,
+                _ => errno(EINVAL)
+            };
+            set_return(frame, ret);
+            mepc + 4
+        },
+        SYS_SCHED_SETATTR => {
+            // sched_setattr(pid, attr, flags). This is the only way to
+            // become a deadline process.
+            let mut attr = [0u8; SCHED_ATTR_SIZE];
+            if with_process_by_frame(frame, |p| p.copy_from_user(args[1], &mut attr)) != Some(true) {
+                set_return(frame, errno(EFAULT));
+                return mepc + 4;
+            }
+            let policy = le_u32(&attr, 4) as usize;
+            let nice = le_u32(&attr, 16) as i32;
+            let prio = le_u32(&attr, 20) as i32;
+            let ret = if policy == SCHED_DEADLINE {
+                let runtime = ns_to_ticks(le_u64(&attr, 24) as usize);
+                let deadline = ns_to_ticks(le_u64(&attr, 32) as usize);
+                // A period of zero means the same as the deadline.
+                let period = match ns_to_ticks(le_u64(&attr, 40) as usize) {
+                    0 => deadline,
+                    p => p
+                };
+                // Periods are counted from mtime, so one has to fit after
+                // it.
+                if runtime == 0 || runtime > deadline || deadline > period
+                   || get_mtime().checked_add(period).is_none() {
+                    errno(EINVAL)
+                }
+                else {
+                    let dl = Deadline::new(runtime, deadline, period);
+                    let pid = with_target(frame, args[0], |p| p.get_pid());
+                    match pid {
+                        Some(pid) if !deadline_admissible(pid, &dl) => errno(EBUSY),
+                        Some(pid) => {
+                            with_process_by_pid(pid, |p| p.set_class(SchedClass::Deadline(dl)));
+                            0
+                        },
+                        None => errno(ESRCH)
+                    }
+                }
+            }
+            else {
+                let ret = with_target(frame, args[0], |p| {
+                    match class_from(policy, prio) {
+                        Some(class) => {
+                            p.set_class(class);
+                            if policy == SCHED_NORMAL {
+                                p.set_nice(nice);
+                            }
+                            0
+                        },
+                        None => errno(EINVAL)
+                    }
+                });
+                ret.unwrap_or(errno(ESRCH))
+            };
+            set_return(frame, ret);
+            preempt_soon();
+            mepc + 4
+        },
+        SYS_SCHED_GETATTR => {
+            // sched_getattr(pid, attr, size, flags)
+            if args[2] < SCHED_ATTR_SIZE {
+                set_return(frame, errno(EINVAL));
+                return mepc + 4;
+            }
+            let attr = with_target(frame, args[0], |p| {
+                let class = p.get_class();
+                let mut attr = [0u8; SCHED_ATTR_SIZE];
+                attr[0..4].copy_from_slice(&(SCHED_ATTR_SIZE as u32).to_le_bytes());
+                attr[4..8].copy_from_slice(&(class.policy() as u32).to_le_bytes());
+                attr[16..20].copy_from_slice(&(p.get_nice() as i32).to_le_bytes());
+                attr[20..24].copy_from_slice(&(class.priority() as u32).to_le_bytes());
+                if let SchedClass::Deadline(dl) = class {
+                    attr[24..32].copy_from_slice(&(ticks_to_ns(dl.get_runtime()) as u64).to_le_bytes());
+                    attr[32..40].copy_from_slice(&(ticks_to_ns(dl.get_deadline()) as u64).to_le_bytes());
+                    attr[40..48].copy_from_slice(&(ticks_to_ns(dl.get_period()) as u64).to_le_bytes());
+                }
+                attr
+            });
+            let ret = match attr {
+                Some(attr) => {
+                    let written = with_process_by_frame(frame, |p| p.copy_to_user(args[1], &attr));
+                    if written == Some(true) { 0 } else { errno(EFAULT) }
+                },
+                None => errno(ESRCH)
+            };
+            set_return(frame, ret);
+            mepc + 4
+        },
+        SYS_BRK => {
+            // brk(addr) -> new break, or the current one if addr is 0
+            // or can't be reached.
+            let ret = with_process_by_frame(frame, |p| {
+                if args[0] == 0 { p.get_brk() } else { p.set_brk(args[0]) }
+            });
+            set_return(frame, ret.unwrap_or(0));
+            mepc + 4
+        },
+        SYS_MUNMAP => {
+            // munmap(addr, len)
+            let ret = with_process_by_frame(frame, |p| p.munmap(args[0], args[1]).map(|_| 0));
+            set_return(frame, map_result(ret));
+            mepc + 4
+        },
+        SYS_MMAP => {
+            // mmap(addr, len, prot, flags)
+            let ret = with_process_by_frame(frame, |p| {
+                p.mmap(args[0], args[1], args[2], args[3])
+            });
+            set_return(frame, map_result(ret));
+            mepc + 4
+        },
+        SYS_MPROTECT => {
+            // mprotect(addr, len, prot)
+            let ret = with_process_by_frame(frame, |p| p.mprotect(args[0], args[1], args[2]).map(|_| 0));
+            set_return(frame, map_result(ret));
+            mepc + 4
+        },
         _ => {
-            print!("Unknown syscall number {}", syscall_number);
+            warn!("Unknown syscall number {}", syscall_number);
             mepc + 4
         }
     }
-}
\ No newline at end of file
+}
+
+// The return value goes back to the process in a0.
+fn set_return(frame: *mut TrapFrame, val: usize) {
+    unsafe { (*frame).regs[10] = val; }
+}
+
+// Run f on the process a pid argument names, where 0 means the caller.
+// PIDs are 16 bits, so there's nobody past that, and None comes back
+// just like for a PID nobody has.
+fn with_target<F, R>(frame: *mut TrapFrame, pid: usize, f: F) -> Option<R>
+    where F: FnOnce(&mut crate::process::Process) -> R
+{
+    if pid == 0 {
+        with_process_by_frame(frame, f)
+    }
+    else if pid > u16::max_value() as usize {
+        None
+    }
+    else {
+        with_process_by_pid(pid as u16, f)
+    }
+}
+
+// Seconds and nanoseconds from user space as nanoseconds, or None if
+// the seconds are negative or it doesn't fit in 64 bits
+fn to_ns(sec: i64, nsec: u64) -> Option<u64> {
+    if sec < 0 {
+        return None;
+    }
+    (sec as u64).checked_mul(1_000_000_000)?.checked_add(nsec)
+}
+
+// What an mmap, munmap or mprotect returns to the process
+fn map_result(ret: Option<Result<usize, MapError>>) -> usize {
+    match ret {
+        Some(Ok(ret)) => ret,
+        Some(Err(MapError::Invalid)) => errno(EINVAL),
+        Some(Err(MapError::NoMemory)) => errno(ENOMEM),
+        None => errno(ESRCH)
+    }
+}
+
+fn group_exists(pgid: u16) -> bool {
+    crate::process::PROCESS_LIST.lock()
+                                .as_ref()
+                                .map_or(false, |pl| pl.iter().any(|p| p.get_pgid() == pgid))
+}
+
+// The scheduling class for a sched_setscheduler policy and priority
+fn class_from(policy: usize, prio: i32) -> Option<SchedClass> {
+    let rt_prio = prio >= RT_PRIO_MIN as i32 && prio <= RT_PRIO_MAX as i32;
+    match policy {
+        SCHED_NORMAL if prio == 0 => Some(SchedClass::Normal),
+        SCHED_FIFO if rt_prio => Some(SchedClass::Fifo(prio as u8)),
+        SCHED_RR if rt_prio => Some(SchedClass::Rr(prio as u8)),
+        _ => None
+    }
+}
+
+// Read a timespec or timeval: two 64-bit fields.
+fn read_pair(frame: *mut TrapFrame, addr: usize) -> Option<(i64, i64)> {
+    let mut buf = [0u8; 16];
+    match with_process_by_frame(frame, |p| p.copy_from_user(addr, &mut buf)) {
+        Some(true) => Some((le_u64(&buf, 0) as i64, le_u64(&buf, 8) as i64)),
+        _ => None
+    }
+}
+
+// Write a timespec or timeval, returning 0 or -EFAULT.
+fn write_pair(frame: *mut TrapFrame, addr: usize, a: u64, b: u64) -> usize {
+    let mut buf = [0u8; 16];
+    buf[0..8].copy_from_slice(&a.to_le_bytes());
+    buf[8..16].copy_from_slice(&b.to_le_bytes());
+    match with_process_by_frame(frame, |p| p.copy_to_user(addr, &buf)) {
+        Some(true) => 0,
+        _ => errno(EFAULT)
+    }
+}
+
+fn le_u32(buf: &[u8], off: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[off..off + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn le_u64(buf: &[u8], off: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[off..off + 8]);
+    u64::from_le_bytes(bytes)
+}
@@ -0,0 +1,167 @@
+// Adam Short
+// 08/04/2020
+
+use crate::cpu::{current_hart, sbi_set_timer, MAX_HARTS};
+use crate::fdt;
+use crate::sync::Spinlock;
+use alloc::vec::Vec;
+
+// The CLINT has one mtime for the whole machine and an mtimecmp for
+// every hart, one after the other.
+const CLINT_MTIMECMP: usize = 0x0200_4000;
+const CLINT_MTIME: usize = 0x0200_bff8;
+// What QEMU's virt board runs mtime at, in case the device tree doesn't
+// tell us
+const DEFAULT_TIMEBASE: usize = 10_000_000;
+const NS_PER_SEC: usize = 1_000_000_000;
+
+// How many times a second mtime ticks
+static mut TIMEBASE: usize = DEFAULT_TIMEBASE;
+// What each hart last asked for in mtimecmp
+static mut ARMED: [usize; MAX_HARTS] = [usize::max_value(); MAX_HARTS];
+
+// Read the timebase out of /cpus. Runs in kinit, after fdt::init.
+pub fn init() {
+    let freq = fdt::find_node("/cpus").and_then(|n| n.prop_u32("timebase-frequency"));
+    if let Some(freq) = freq.filter(|&f| f != 0) {
+        unsafe { TIMEBASE = freq as usize; }
+    }
+}
+
+pub fn timebase() -> usize {
+    unsafe { TIMEBASE }
+}
+
+pub fn get_mtime() -> usize {
+    unsafe { (CLINT_MTIME as *const u64).read_volatile() as usize }
+}
+
+// These go through u128 so that long times at a fast timebase don't
+// overflow on the way.
+pub fn ticks_to_ns(ticks: usize) -> usize {
+    (ticks as u128 * NS_PER_SEC as u128 / timebase() as u128) as usize
+}
+
+pub fn ns_to_ticks(ns: usize) -> usize {
+    (ns as u128 * timebase() as u128 / NS_PER_SEC as u128) as usize
+}
+
+pub fn us_to_ticks(us: usize) -> usize {
+    (us as u128 * timebase() as u128 / 1_000_000) as usize
+}
+
+pub fn ms_to_ticks(ms: usize) -> usize {
+    (ms as u128 * timebase() as u128 / 1_000) as usize
+}
+
+// Write a hart's mtimecmp. Only machine mode can get at it, so this is
+// for m_trap. The kernel uses arm().
+pub fn set_mtimecmp(hart: usize, when: u64) {
+    unsafe {
+        (CLINT_MTIMECMP as *mut u64).add(hart).write_volatile(when);
+    }
+}
+
+// ///////////////////////////////////
+// / KERNEL TIMERS
+// ///////////////////////////////////
+
+pub type TimerCallback = fn(usize);
+
+#[derive(Clone, Copy)]
+struct Timer {
+    id: usize,
+    hart: usize,
+    deadline: usize,
+    // 0 for a one-shot timer
+    period: usize,
+    callback: TimerCallback,
+    arg: usize,
+}
+
+// Every pending kernel timer on every hart, soonest first
+static TIMERS: Spinlock<Vec<Timer>> = Spinlock::new(Vec::new());
+static NEXT_TIMER_ID: Spinlock<usize> = Spinlock::new(1);
+
+fn insert(timers: &mut Vec<Timer>, timer: Timer) {
+    let pos = timers.iter()
+                    .position(|t| t.deadline > timer.deadline)
+                    .unwrap_or(timers.len());
+    timers.insert(pos, timer);
+}
+
+// Call callback(arg) on this hart once mtime reaches deadline. If period
+// isn't 0, it's called again every period ticks after that until the
+// timer is cancelled. Callbacks run in the timer interrupt, so they must
+// not block. Returns an id for cancel_timer.
+pub fn add_timer_at(deadline: usize, period: usize, callback: TimerCallback, arg: usize) -> usize {
+    let id = {
+        let mut next = NEXT_TIMER_ID.lock();
+        *next += 1;
+        *next - 1
+    };
+    let hart = current_hart();
+    insert(&mut TIMERS.lock(), Timer { id, hart, deadline, period, callback, arg });
+    // If the hart's timer is set for later than this, bring it forward.
+    if deadline < unsafe { ARMED[hart] } {
+        arm(hart, deadline);
+    }
+    id
+}
+
+// Same as add_timer_at, but delay ticks from now
+pub fn add_timer(delay: usize, period: usize, callback: TimerCallback, arg: usize) -> usize {
+    add_timer_at(get_mtime() + delay, period, callback, arg)
+}
+
+// Returns false if the timer already went off (and wasn't periodic).
+pub fn cancel_timer(id: usize) -> bool {
+    let mut timers = TIMERS.lock();
+    match timers.iter().position(|t| t.id == id) {
+        Some(pos) => { timers.remove(pos); true },
+        None => false
+    }
+}
+
+pub fn next_deadline(hart: usize) -> Option<usize> {
+    TIMERS.lock().iter().find(|t| t.hart == hart).map(|t| t.deadline)
+}
+
+// Run every timer on this hart that's due. We let go of the lock for
+// each callback, so callbacks can add and cancel timers themselves.
+pub fn run_expired(hart: usize) {
+    loop {
+        let now = get_mtime();
+        let due = {
+            let mut timers = TIMERS.lock();
+            match timers.iter().position(|t| t.hart == hart && t.deadline <= now) {
+                Some(pos) => {
+                    let timer = timers.remove(pos);
+                    if timer.period != 0 {
+                        // If we fell behind, skip the periods we missed
+                        // rather than firing for each of them.
+                        let mut next = timer;
+                        while next.deadline <= now {
+                            next.deadline += timer.period;
+                        }
+                        insert(&mut timers, next);
+                    }
+                    Some(timer)
+                },
+                None => None
+            }
+        };
+        match due {
+            Some(timer) => (timer.callback)(timer.arg),
+            None => break
+        }
+    }
+}
+
+// Program this hart's timer for when, or for its next kernel timer if
+// that comes first.
+pub fn arm(hart: usize, when: usize) {
+    let when = core::cmp::min(when, next_deadline(hart).unwrap_or(usize::max_value()));
+    unsafe { ARMED[hart] = when; }
+    sbi_set_timer(when as u64);
+}
@@ -1,15 +1,42 @@
 // Adam Short
 // 08/02/2020
 
-use crate::cpu::TrapFrame;
-use crate::{plic, uart};
+use crate::backtrace::{dump_registers, print_backtrace_from};
+use crate::cpu::{self, TrapFrame};
+use crate::{abort, console, gdbstub, ipi, irq, timer};
 use crate::syscall::do_syscall;
-use crate::sched::schedule;
+use crate::sched::{account_idle, reschedule};
 
-extern "C" {
-	fn switch_to_user(frame: usize, mepc: usize, satp: usize) -> !;
+// Every hart has its own software interrupt register in the CLINT.
+const CLINT_MSIP: usize = 0x0200_0000;
+
+// Where the mode a trap came from is kept in mstatus (MPP) and sstatus
+// (SPP)
+const MSTATUS_MPP: usize = 3 << 11;
+const SSTATUS_SPP: usize = 1 << 8;
+
+// A trap we can't do anything about. Print the registers and, if it
+// came from the kernel, where it came from, then stop this hart. A
+// process' stack isn't mapped in the kernel's page table, so there's no
+// walking that.
+fn fatal_trap(what: &str, cause: usize, epc: usize, tval: usize, hart: usize, from_user: bool,
+              frame: *mut TrapFrame) -> ! {
+    // Whatever this hart was in the middle of, it isn't coming back to
+    // let go of the console or the log buffer.
+    console::start_panic();
+    println!("{} CPU#{} -> cause {} at 0x{:08x}: 0x{:08x}{}", what, hart, cause, epc, tval,
+             if from_user { " (user mode)" } else { "" });
+    let frame = unsafe { &*frame };
+    dump_registers(frame);
+    if !from_user {
+        print_backtrace_from(epc, frame.regs[8]);
+    }
+    abort();
 }
 
+// Machine mode only keeps what supervisor mode can't do for itself:
+// it forwards the timer and software interrupts and programs mtimecmp
+// when the kernel asks with an ecall.
 #[no_mangle]
 extern "C" fn m_trap(
     epc: usize,
@@ -19,6 +46,65 @@ extern "C" fn m_trap(
     status: usize,
     frame: *mut TrapFrame,
 ) -> usize {
+
+    let is_async = cause >> 63 & 1 == 1;
+    let cause_num = cause & 0xfff;
+    let mut return_pc = epc;
+
+    if is_async {
+        match cause_num {
+            // Machine software interrupt: another hart sent us an IPI.
+            // Acknowledge it in the CLINT first, so a message that
+            // arrives while we're handling the others raises it again.
+            3 => unsafe {
+                let msip = (CLINT_MSIP as *mut u32).add(hart);
+                msip.write_volatile(0);
+                ipi::handle(hart);
+            },
+            // Machine timer. MTIP stays up until mtimecmp is written
+            // again, so mask it until the kernel does that through
+            // SBI_SET_TIMER.
+            7 => {
+                cpu::mie_clear(cpu::MTIP);
+                cpu::mip_set(cpu::STIP);
+            },
+            _ => { panic!("Unhandled machine interrupt! CPU#{} -> {}\n", hart, cause_num); }
+        }
+    }
+    else {
+        match cause_num {
+            // E-call from Supervisor mode
+            9 => unsafe {
+                match (*frame).regs[17] {
+                    cpu::SBI_SET_TIMER => {
+                        timer::set_mtimecmp(hart, (*frame).regs[10] as u64);
+                        cpu::mip_clear(cpu::STIP);
+                        cpu::mie_set(cpu::MTIP);
+                    },
+                    // No printing here: the kernel may have been
+                    // holding the console lock when it made the call.
+                    _ => { (*frame).regs[10] = cpu::SBI_ERR_NOT_SUPPORTED as usize; }
+                }
+                return_pc += 4;
+            },
+            _ => {
+                let from_user = status & MSTATUS_MPP == 0;
+                fatal_trap("Unhandled machine trap!", cause_num, epc, tval, hart, from_user, frame);
+            }
+        }
+    }
+    return_pc
+}
+
+#[no_mangle]
+extern "C" fn s_trap(
+    epc: usize,
+    tval: usize,
+    cause: usize,
+    hart: usize,
+    status: usize,
+    frame: *mut TrapFrame,
+) -> usize {
     
     let is_async = {
         if cause >> 63 & 1 == 1 {
@@ -31,47 +117,28 @@ extern "C" fn m_trap(
 
     let cause_num = cause & 0xfff;
     let mut return_pc = epc;
+    let from_user = status & SSTATUS_SPP == 0;
+
+    account_idle(frame, hart);
 
     if is_async {
         match cause_num {
-            3 => { println!("Machine software interrupt! CPU#{}", hart); },
-            // Machine timer
-            7 => unsafe {
-              let (frame, mepc, satp) = schedule();
-              let mtimecmp = 0x0200_4000 as *mut u64;
-              let mtime = 0x0200_bff8 as *const u64;
-              mtimecmp.write_volatile(mtime.read_volatile() + 10_000_000);
-              switch_to_user(frame, mepc, satp);
+            // Supervisor software interrupt, raised by a reschedule IPI
+            // in m_trap.
+            1 => {
+                cpu::sip_clear(cpu::SSIP);
+                reschedule(hart);
             },
-			      // Interrupt from PLIC
-            11 => {
-              if let Some(interrupt) = plic::next() {
-                match interrupt {
-                  // UART interrupt!
-                  10 => {
-                    let mut my_uart = uart::Uart::new(0x1000_0000);
-                    if let Some(c) = my_uart.get() {
-                      match c {
-                        // Backspace
-                        8 | 127 => {
-                          print!("{} {}", 8 as char, 8 as char);
-                        },
-                        // \n or \r
-                        10 | 13 => {
-                          println!();
-                        },
-                        _ => {
-                          print!("{}", c as char);
-                        }
-                      }
-                    }
-                  },
-                  _ => {
-                    println!("Non-UART external input: {}", interrupt);
-                  }
-                }
-                plic::complete(interrupt);
-              }
+            // Supervisor timer, forwarded from m_trap. Run the kernel
+            // timers that are due, then schedule(), which also programs
+            // the next timer interrupt.
+            5 => {
+              timer::run_expired(hart);
+              reschedule(hart);
+            },
+            // Interrupt from the PLIC
+            9 => {
+              irq::handle(hart);
             },
             _ => { panic!("Unhandled async trap! CPU#{} -> {}\n", hart, cause_num); }
         }
@@ -79,34 +146,37 @@ extern "C" fn m_trap(
     else {
         match cause_num {
             2 => { 
-              panic!("Illegal instruction! CPU#{} -> 0x{:08x}", hart, epc); 
-              while true {}
-            
+              // Processes start with the FPU off. If this one hasn't
+              // had it yet, this is most likely its first FP
+              // instruction, so turn it on and try again.
+              if unsafe { (*frame).fp_state } == cpu::FS_OFF {
+                cpu::fp_first_use(frame, hart);
+                return return_pc;
+              }
+              fatal_trap("Illegal instruction!", cause_num, epc, tval, hart, from_user, frame);
             },
-            8 => {
-                return_pc = do_syscall(return_pc, frame);
+            // ebreak from a process. medeleg sends breakpoints here
+            // rather than to m_trap, and the GDB stub, if anybody is
+            // attached, stops everything.
+            3 if from_user => {
+                gdbstub::breakpoint(frame);
+                reschedule(hart);
             },
-            9 => {
-                println!("E-call from Supervisor mode! CPU#{} -> 0x{:08x}", hart, epc);
+            8 => {
                 return_pc = do_syscall(return_pc, frame);
             },
-            11 => { println!("E-call from Machine mode! CPU#{} -> 0x{:08x}", hart, epc); },
             12 => {
-                println!("Instruction page fault! CPU#{} -> 0x{:08x}", hart, epc);
-                while true {}
-                return_pc += 4;
+                fatal_trap("Instruction page fault!", cause_num, epc, tval, hart, from_user, frame);
             },
             13 => {
-                println!("Load page fault CPU#{} -> 0x{:08x}: 0x{:08x}", hart, epc, tval);
-                while true {}
-                return_pc += 4;
+                fatal_trap("Load page fault!", cause_num, epc, tval, hart, from_user, frame);
             },
             15 => {
-                println!("Store page fault! CPU#{} -> 0x{:08x}: 0x{:08x}", hart, epc, tval);
-                while true {}
-                return_pc += 4;
+                fatal_trap("Store page fault!", cause_num, epc, tval, hart, from_user, frame);
             },
-            _ => { panic!("Unhandled sync trap! CPU#{} -> {}\n", hart, cause_num); }
+            _ => {
+                fatal_trap("Unhandled sync trap!", cause_num, epc, tval, hart, from_user, frame);
+            }
         }
     };
     return_pc
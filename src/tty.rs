@@ -0,0 +1,364 @@
+// Adam Short
+// 08/04/2020
+
+use crate::cpu::TrapFrame;
+use crate::signal::{send_group, SIGINT, SIGQUIT, SIGTSTP};
+use crate::sync::{Spinlock, WaitQueue};
+use crate::uart::{Port, UART0};
+use alloc::vec::Vec;
+
+// termios flags, with the values Linux gives them
+pub const ICRNL: u32 = 0o000400;
+pub const OPOST: u32 = 0o000001;
+pub const ONLCR: u32 = 0o000004;
+pub const ISIG: u32 = 0o000001;
+pub const ICANON: u32 = 0o000002;
+pub const ECHO: u32 = 0o000010;
+pub const ECHOE: u32 = 0o000020;
+pub const ECHOK: u32 = 0o000040;
+pub const ECHOCTL: u32 = 0o001000;
+pub const IEXTEN: u32 = 0o100000;
+
+// Where each special character lives in c_cc
+pub const VINTR: usize = 0;
+pub const VQUIT: usize = 1;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VTIME: usize = 5;
+pub const VMIN: usize = 6;
+pub const VSUSP: usize = 10;
+pub const VWERASE: usize = 14;
+pub const NCCS: usize = 19;
+
+// ioctl requests
+pub const TCGETS: usize = 0x5401;
+pub const TCSETS: usize = 0x5402;
+pub const TCSETSW: usize = 0x5403;
+pub const TCSETSF: usize = 0x5404;
+pub const TIOCGPGRP: usize = 0x540f;
+pub const TIOCSPGRP: usize = 0x5410;
+
+// How a termios looks in user memory: four flag words, the line
+// discipline and the special characters
+pub const TERMIOS_SIZE: usize = 4 * 4 + 1 + NCCS;
+
+// The longest line canonical mode holds on to. Typing past it rings
+// nobody's bell, it just doesn't go in.
+const MAX_LINE: usize = 255;
+
+#[derive(Clone, Copy)]
+pub struct Termios {
+    pub iflag: u32,
+    pub oflag: u32,
+    pub cflag: u32,
+    pub lflag: u32,
+    pub line: u8,
+    pub cc: [u8; NCCS],
+}
+
+impl Termios {
+    // What a terminal starts out as: line at a time, with echo and
+    // signals, and \n going out as \r\n.
+    // The special characters are ^C, ^\, DEL, ^U, ^D, ^Z and ^W.
+    pub const fn default() -> Self {
+        // VINTR, VQUIT, VERASE, VKILL, VEOF, VTIME, VMIN, VSWTC, VSTART,
+        // VSTOP, VSUSP, VEOL, VREPRINT, VDISCARD, VWERASE, ...
+        let cc = [3, 28, 127, 21, 4, 0, 1, 0, 17, 19, 26, 0, 18, 15, 23, 22, 0, 0, 0];
+        Termios {
+            iflag: ICRNL,
+            oflag: OPOST | ONLCR,
+            cflag: 0,
+            lflag: ISIG | ICANON | ECHO | ECHOE | ECHOK | ECHOCTL | IEXTEN,
+            line: 0,
+            cc,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; TERMIOS_SIZE] {
+        let mut buf = [0u8; TERMIOS_SIZE];
+        buf[0..4].copy_from_slice(&self.iflag.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.oflag.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.cflag.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.lflag.to_le_bytes());
+        buf[16] = self.line;
+        buf[17..].copy_from_slice(&self.cc);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; TERMIOS_SIZE]) -> Self {
+        let word = |off: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&buf[off..off + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        let mut cc = [0u8; NCCS];
+        cc.copy_from_slice(&buf[17..]);
+        Termios { iflag: word(0), oflag: word(4), cflag: word(8), lflag: word(12), line: buf[16], cc }
+    }
+
+    fn is_canonical(&self) -> bool {
+        self.lflag & ICANON != 0
+    }
+}
+
+struct TtyState {
+    termios: Termios,
+    // The line being typed, in canonical mode
+    editing: Vec<u8>,
+    // Finished lines waiting for read(). ^D on an empty line leaves an
+    // empty one, which read() returns as end of file.
+    lines: Vec<Vec<u8>>,
+    // Everything typed in raw mode
+    raw: Vec<u8>,
+    // The process group that gets ^C and friends. 0 is nobody.
+    foreground: u16,
+}
+
+// A terminal on top of a UART port. The port hands us every byte as it
+// comes in, and we do the line editing, echo and job control before
+// anything gets to read().
+pub struct Tty {
+    port: &'static Port,
+    state: Spinlock<TtyState>,
+    readers: WaitQueue,
+}
+
+pub static TTY0: Tty = Tty::new(&UART0);
+
+impl Tty {
+    pub const fn new(port: &'static Port) -> Self {
+        Tty {
+            port,
+            state: Spinlock::new(TtyState {
+                termios: Termios::default(),
+                editing: Vec::new(),
+                lines: Vec::new(),
+                raw: Vec::new(),
+                foreground: 0,
+            }),
+            readers: WaitQueue::new(),
+        }
+    }
+
+    // Take one byte from the port. This runs in the UART's interrupt.
+    pub fn input(&self, c: u8) {
+        let mut state = self.state.lock();
+        let t = state.termios;
+        let c = if c == b'\r' && t.iflag & ICRNL != 0 { b'\n' } else { c };
+
+        if t.lflag & ISIG != 0 {
+            let sig = if c == t.cc[VINTR] { Some(SIGINT) }
+                      else if c == t.cc[VQUIT] { Some(SIGQUIT) }
+                      else if c == t.cc[VSUSP] { Some(SIGTSTP) }
+                      else { None };
+            if let Some(sig) = sig {
+                state.editing.clear();
+                let foreground = state.foreground;
+                drop(state);
+                if t.lflag & ECHO != 0 {
+                    self.echo_ctl(&t, c);
+                    self.output(&t, b"\n");
+                }
+                if foreground != 0 {
+                    send_group(foreground, sig);
+                }
+                return;
+            }
+        }
+
+        if !t.is_canonical() {
+            state.raw.push(c);
+            drop(state);
+            if t.lflag & ECHO != 0 {
+                self.output(&t, &[c]);
+            }
+            self.readers.wake_all();
+            return;
+        }
+
+        let echo = t.lflag & ECHO != 0;
+        if c == t.cc[VERASE] || c == 8 {
+            if state.editing.pop().is_some() && echo && t.lflag & ECHOE != 0 {
+                self.port.write(b"\x08 \x08");
+            }
+        }
+        else if c == t.cc[VKILL] {
+            let n = state.editing.len();
+            state.editing.clear();
+            if echo && t.lflag & ECHOK != 0 {
+                self.erase(n);
+            }
+        }
+        else if c == t.cc[VWERASE] && t.lflag & IEXTEN != 0 {
+            // Spaces first, then the word before them
+            let line = &mut state.editing;
+            let mut n = 0;
+            while line.last() == Some(&b' ') {
+                line.pop();
+                n += 1;
+            }
+            while line.last().map_or(false, |&c| c != b' ') {
+                line.pop();
+                n += 1;
+            }
+            if echo && t.lflag & ECHOE != 0 {
+                self.erase(n);
+            }
+        }
+        else if c == t.cc[VEOF] {
+            // Whatever has been typed goes to read() without a newline.
+            // If nothing has, read() gets 0, which is end of file.
+            let line = core::mem::replace(&mut state.editing, Vec::new());
+            state.lines.push(line);
+            drop(state);
+            self.readers.wake_all();
+        }
+        else if c == b'\n' {
+            let mut line = core::mem::replace(&mut state.editing, Vec::new());
+            line.push(b'\n');
+            state.lines.push(line);
+            drop(state);
+            if echo {
+                self.output(&t, b"\n");
+            }
+            self.readers.wake_all();
+        }
+        else if state.editing.len() < MAX_LINE {
+            state.editing.push(c);
+            drop(state);
+            if echo {
+                self.echo_ctl(&t, c);
+            }
+        }
+    }
+
+    // Read for the process that owns frame. In canonical mode that's a
+    // line at a time, otherwise whatever has been typed. Either way, if
+    // there's nothing yet, the process sleeps and the system call
+    // starts over once there is.
+    pub fn read(&self, frame: *mut TrapFrame, buf: &mut [u8]) -> usize {
+        let mut state = self.state.lock();
+        if buf.is_empty() {
+            return 0;
+        }
+        if state.termios.is_canonical() {
+            if state.lines.is_empty() {
+                self.readers.wait(frame, state);
+            }
+            let mut line = state.lines.remove(0);
+            let n = line.len().min(buf.len());
+            buf[..n].copy_from_slice(&line[..n]);
+            // What didn't fit is the start of the next read.
+            if n < line.len() {
+                state.lines.insert(0, line.split_off(n));
+            }
+            n
+        }
+        else {
+            // VMIN and VTIME aren't supported; raw reads wait for one
+            // byte, like VMIN = 1 and VTIME = 0.
+            if state.raw.is_empty() {
+                self.readers.wait(frame, state);
+            }
+            let n = state.raw.len().min(buf.len());
+            buf[..n].copy_from_slice(&state.raw[..n]);
+            state.raw.drain(..n);
+            n
+        }
+    }
+
+    // Output from a process, with \n turned into \r\n if ONLCR says so.
+    // It goes out in one piece, so buf can be at most half the port's
+    // buffer.
+    pub fn write(&self, frame: *mut TrapFrame, buf: &[u8]) {
+        let t = self.state.lock().termios;
+        let mut out = Vec::with_capacity(buf.len() * 2);
+        translate(&t, buf, &mut out);
+        self.port.write_wait(frame, &out);
+    }
+
+    pub fn get_termios(&self) -> Termios {
+        self.state.lock().termios
+    }
+
+    // Switch modes. Anything typed so far is kept: going to raw mode,
+    // it's all readable straight away, and going to canonical mode, it
+    // becomes the start of the line.
+    pub fn set_termios(&self, termios: Termios, flush: bool) {
+        let mut state = self.state.lock();
+        let was_canonical = state.termios.is_canonical();
+        state.termios = termios;
+        if flush {
+            state.editing.clear();
+            state.lines.clear();
+            state.raw.clear();
+        }
+        else if was_canonical && !termios.is_canonical() {
+            let lines = core::mem::replace(&mut state.lines, Vec::new());
+            let editing = core::mem::replace(&mut state.editing, Vec::new());
+            for line in lines.into_iter().chain(core::iter::once(editing)) {
+                state.raw.extend(line);
+            }
+        }
+        else if !was_canonical && termios.is_canonical() {
+            let raw = core::mem::replace(&mut state.raw, Vec::new());
+            state.editing.extend(raw);
+            state.editing.truncate(MAX_LINE);
+        }
+        drop(state);
+        self.readers.wake_all();
+    }
+
+    pub fn get_foreground(&self) -> u16 {
+        self.state.lock().foreground
+    }
+
+    pub fn set_foreground(&self, pgid: u16) {
+        self.state.lock().foreground = pgid;
+    }
+
+    fn output(&self, t: &Termios, buf: &[u8]) {
+        let mut out = Vec::with_capacity(buf.len() * 2);
+        translate(t, buf, &mut out);
+        // Echo never waits. If the port is that far behind, it's lost.
+        self.port.write(&out);
+    }
+
+    // Control characters echo as ^X if ECHOCTL is on.
+    fn echo_ctl(&self, t: &Termios, c: u8) {
+        if c < b' ' && c != b'\t' && t.lflag & ECHOCTL != 0 {
+            self.port.write(&[b'^', c + b'@']);
+        }
+        else {
+            self.output(t, &[c]);
+        }
+    }
+
+    fn erase(&self, n: usize) {
+        for _ in 0..n {
+            self.port.write(b"\x08 \x08");
+        }
+    }
+}
+
+fn translate(t: &Termios, buf: &[u8], out: &mut Vec<u8>) {
+    let onlcr = t.oflag & OPOST != 0 && t.oflag & ONLCR != 0;
+    for &c in buf {
+        if c == b'\n' && onlcr {
+            out.push(b'\r');
+        }
+        out.push(c);
+    }
+}
+
+// Attach the console tty to its port. The first process is in the
+// foreground until somebody says otherwise.
+pub fn init() {
+    UART0.set_input(input);
+    TTY0.set_foreground(1);
+}
+
+fn input(c: u8) {
+    TTY0.input(c);
+}
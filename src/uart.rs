@@ -1,8 +1,27 @@
 // Adam Short
 // 08/02/2020
 
+use crate::cpu::TrapFrame;
+use crate::fdt;
+use crate::irq::register_irq;
+use crate::sync::{Spinlock, WaitQueue};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::convert::TryInto;
-use core::fmt::{Error, Write};
+use core::fmt::{Arguments, Error, Write};
+
+// 16550 registers, as offsets from the base address
+const RBR: usize = 0; // Receive buffer (read)
+const THR: usize = 0; // Transmit holding (write)
+const IER: usize = 1; // Interrupt enable
+const LSR: usize = 5; // Line status
+
+const IER_RDI: u8 = 1 << 0; // Data received
+const IER_THRI: u8 = 1 << 1; // THR empty
+const LSR_DR: u8 = 1 << 0; // Data ready
+const LSR_THRE: u8 = 1 << 5; // THR empty
+// How many bytes the transmit FIFO holds once THRE says it's empty
+const TX_FIFO_SIZE: usize = 16;
 
 pub struct Uart {
     base_address: usize,
@@ -42,7 +61,7 @@ impl Uart {
             let lcr = 0b11;
             ptr.add(3).write_volatile(lcr);
             ptr.add(2).write_volatile(0b01);
-            ptr.add(1).write_volatile(0b01);
+            ptr.add(IER).write_volatile(IER_RDI);
 
             let divisor: u16 = 592;
             let divisor_least: u8 = (divisor & 0xff).try_into().unwrap();
@@ -74,13 +93,319 @@ impl Uart {
 
     ////////////////////////////////////////////////////////////////////////////
     // Write to the UART
+    // Wait for THRE (transmit holding register empty) first, otherwise the
+    // byte is lost if the UART is still busy with the last one
     ////////////////////////////////////////////////////////////////////////////
     pub fn put(&mut self, c: u8) {
         let ptr = self.base_address as *mut u8;
         unsafe {
-            ptr.add(0).write_volatile(c);
+            while ptr.add(LSR).read_volatile() & LSR_THRE == 0 {}
+            ptr.add(THR).write_volatile(c);
         }
     }
-    
+
+    fn is_tx_empty(&self) -> bool {
+        unsafe { (self.base_address as *const u8).add(LSR).read_volatile() & LSR_THRE != 0 }
+    }
+
+    fn set_ier(&mut self, bits: u8, on: bool) {
+        let ptr = self.base_address as *mut u8;
+        unsafe {
+            let ier = ptr.add(IER).read_volatile();
+            ptr.add(IER).write_volatile(if on { ier | bits } else { ier & !bits });
+        }
+    }
+
 }
 
+// ///////////////////////////////////
+// / RING BUFFER
+// ///////////////////////////////////
+
+pub const RING_SIZE: usize = 1024;
+
+struct RingBuffer {
+    buf: [u8; RING_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    const fn new() -> Self {
+        RingBuffer { buf: [0; RING_SIZE], head: 0, len: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn space(&self) -> usize {
+        RING_SIZE - self.len
+    }
+
+    fn push(&mut self, c: u8) -> bool {
+        if self.len == RING_SIZE {
+            return false;
+        }
+        self.buf[(self.head + self.len) % RING_SIZE] = c;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let c = self.buf[self.head];
+        self.head = (self.head + 1) % RING_SIZE;
+        self.len -= 1;
+        Some(c)
+    }
+}
+
+// ///////////////////////////////////
+// / INTERRUPT-DRIVEN PORT
+// ///////////////////////////////////
+
+// The interrupt QEMU's virt board gives the first UART
+pub const UART_IRQ: u32 = 10;
+
+// Something that wants every byte as it comes in, like a tty
+pub type InputHook = fn(u8);
+
+struct PortState {
+    uart: Uart,
+    rx: RingBuffer,
+    tx: RingBuffer,
+    input: Option<InputHook>,
+}
+
+// A UART that the receive interrupt fills a buffer for and the THR empty
+// interrupt drains a buffer from. Processes that can't read or write yet
+// sleep on readers or writers.
+pub struct Port {
+    base_address: usize,
+    state: Spinlock<PortState>,
+    readers: WaitQueue,
+    writers: WaitQueue,
+}
+
+pub static UART0: Port = Port::new(0x1000_0000);
+
+impl Port {
+    pub const fn new(base_address: usize) -> Self {
+        Port {
+            base_address,
+            state: Spinlock::new(PortState {
+                uart: Uart { base_address },
+                rx: RingBuffer::new(),
+                tx: RingBuffer::new(),
+                input: None,
+            }),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+        }
+    }
+
+    pub fn base_address(&self) -> usize {
+        self.base_address
+    }
+
+    pub fn init(&self) {
+        self.state.lock().uart.init();
+    }
+
+    // Hand input to hook instead of buffering it for read().
+    pub fn set_input(&self, hook: InputHook) {
+        self.state.lock().input = Some(hook);
+    }
+
+    // Read whatever has come in, up to buf.len() bytes. Never waits, so
+    // this can return 0.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let mut state = self.state.lock();
+        read_from(&mut state.rx, buf)
+    }
+
+    // Read at least one byte. If nothing has come in yet, the process
+    // that owns frame sleeps until something does and its system call
+    // starts over.
+    pub fn read_wait(&self, frame: *mut TrapFrame, buf: &mut [u8]) -> usize {
+        let mut state = self.state.lock();
+        if state.rx.is_empty() && !buf.is_empty() {
+            self.readers.wait(frame, state);
+        }
+        read_from(&mut state.rx, buf)
+    }
+
+    // Queue buf to go out if all of it fits, returning false if it
+    // doesn't. A write is never split, so as long as writers hand us a
+    // line at a time, lines from different processes don't run into
+    // each other.
+    pub fn write(&self, buf: &[u8]) -> bool {
+        let mut state = self.state.lock();
+        if buf.len() > state.tx.space() {
+            return false;
+        }
+        for &c in buf {
+            state.tx.push(c);
+        }
+        start_tx(&mut state);
+        true
+    }
+
+    // The same, but sleep until there's room. buf can't be bigger than
+    // the buffer, or there never will be.
+    pub fn write_wait(&self, frame: *mut TrapFrame, buf: &[u8]) {
+        let mut state = self.state.lock();
+        if buf.len() > state.tx.space() {
+            self.writers.wait(frame, state);
+        }
+        for &c in buf {
+            state.tx.push(c);
+        }
+        start_tx(&mut state);
+    }
+
+    // Write straight to the UART, after whatever is already queued, and
+    // wait for it to go out. This is for the kernel's own output, which
+    // can't sleep.
+    pub fn write_sync(&self, args: Arguments) {
+        let mut state = self.state.lock();
+        let PortState { uart, tx, .. } = &mut *state;
+        while let Some(c) = tx.pop() {
+            uart.put(c);
+        }
+        let _ = uart.write_fmt(args);
+    }
+
+    pub fn write_str_sync(&self, s: &str) {
+        self.write_sync(format_args!("{}", s));
+    }
+
+    // For when the kernel is going down: straight to the UART without
+    // the lock, which the hart that panicked might be holding. Whatever
+    // is still queued stays there.
+    pub fn write_panic(&self, args: Arguments) {
+        let _ = Uart { base_address: self.base_address }.write_fmt(args);
+    }
+
+    // Called from the UART's interrupt: take in everything that has
+    // arrived and refill the transmit FIFO.
+    pub fn handle_irq(&self) {
+        let mut state = self.state.lock();
+        let mut received = false;
+        // A hook can write to this port, so it runs once we've let go
+        // of the lock. Anything past what we have room for here stays
+        // in the FIFO and interrupts again.
+        let mut hooked = [0u8; TX_FIFO_SIZE];
+        let mut nhooked = 0;
+        while nhooked < hooked.len() {
+            let c = match state.uart.get() {
+                Some(c) => c,
+                None => break
+            };
+            if state.input.is_some() {
+                hooked[nhooked] = c;
+                nhooked += 1;
+            }
+            // Nobody is reading fast enough, so the oldest input wins.
+            else if state.rx.push(c) {
+                received = true;
+            }
+        }
+        let input = state.input;
+        let space = state.tx.space();
+        start_tx(&mut state);
+        let drained = state.tx.space() > space;
+        drop(state);
+        if let Some(input) = input {
+            for &c in &hooked[..nhooked] {
+                input(c);
+            }
+        }
+        if received {
+            self.readers.wake_all();
+        }
+        if drained {
+            self.writers.wake_all();
+        }
+    }
+}
+
+fn read_from(rx: &mut RingBuffer, buf: &mut [u8]) -> usize {
+    let mut n = 0;
+    while n < buf.len() {
+        match rx.pop() {
+            Some(c) => { buf[n] = c; n += 1; },
+            None => break
+        }
+    }
+    n
+}
+
+// Fill the transmit FIFO if it's empty, and keep the THR empty interrupt
+// on for as long as there's more to send.
+fn start_tx(state: &mut PortState) {
+    if state.uart.is_tx_empty() {
+        for _ in 0..TX_FIFO_SIZE {
+            match state.tx.pop() {
+                Some(c) => unsafe {
+                    (state.uart.base_address as *mut u8).add(THR).write_volatile(c);
+                },
+                None => break
+            }
+        }
+    }
+    let more = !state.tx.is_empty();
+    state.uart.set_ier(IER_THRI, more);
+}
+
+// Every UART in the device tree, lowest address first, with the
+// interrupt it raises. The first one is UART0, which is what the kernel
+// has been printing to since it started.
+static PORTS: Spinlock<Vec<(&'static Port, u32)>> = Spinlock::new(Vec::new());
+
+// Find the UARTs and set them up. Runs in kinit, once the heap is up,
+// and before the kernel's page table is built, since that maps them.
+pub fn probe() {
+    let mut found: Vec<(usize, u32)> =
+        fdt::find_compatible("ns16550a").into_iter()
+            .filter_map(|n| n.reg().map(|(addr, _)| (addr, n.prop_u32("interrupts").unwrap_or(0))))
+            .collect();
+    if !found.iter().any(|&(addr, _)| addr == UART0.base_address()) {
+        found.push((UART0.base_address(), UART_IRQ));
+    }
+    found.sort();
+    let mut ports = PORTS.lock();
+    for (addr, irq) in found {
+        let port: &'static Port = if addr == UART0.base_address() {
+            &UART0
+        }
+        else {
+            Box::leak(Box::new(Port::new(addr)))
+        };
+        port.init();
+        ports.push((port, irq));
+    }
+}
+
+pub fn ports() -> Vec<&'static Port> {
+    PORTS.lock().iter().map(|&(port, _)| port).collect()
+}
+
+// Turn on the receive interrupt for every port. Runs in kmain, once the
+// PLIC is set up.
+pub fn register_irqs() {
+    let irqs: Vec<u32> = PORTS.lock().iter().map(|&(_, irq)| irq).collect();
+    for irq in irqs.into_iter().filter(|&irq| irq != 0) {
+        register_irq(irq, handle_irq, 1);
+    }
+}
+
+pub fn handle_irq(irq: u32) {
+    let port = PORTS.lock().iter().find(|&&(_, i)| i == irq).map(|&(port, _)| port);
+    if let Some(port) = port {
+        port.handle_irq();
+    }
+}